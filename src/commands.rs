@@ -0,0 +1,258 @@
+//! Subcommands of piper besides running the pipeline
+use crate::derivation_graph::DerivationGraph;
+use crate::derivation_graph::derivation::{Derivation, DerivationHash};
+use crate::derivation_graph::derivation_runner::RunError;
+use crate::store::optimise::Savings;
+use crate::store::{Status, Store};
+
+/// `piper repair <hash>`: rebuild an entry whose `out` no longer matches
+/// the manifest recorded when it finished
+pub fn repair(
+    dag: &DerivationGraph,
+    hash: &DerivationHash,
+) -> Result<(), RunError> {
+    if dag.get(hash).is_none() {
+        return Err(RunError::NotInGraph(hash.clone()));
+    }
+    let store = Store::new(dag.config.work_dir());
+    let entry = store.entry(hash);
+    if entry.is_finished() {
+        match entry.is_intact() {
+            Ok(true) => {
+                println!("{}: intact, nothing to repair", hash);
+                return Ok(());
+            }
+            Ok(false) => println!("{}: out was modified, rebuilding", hash),
+            Err(e) => {
+                println!("{}: couldn't check out ({}), rebuilding", hash, e)
+            }
+        }
+    }
+    if entry.path.exists() {
+        store.remove(&entry).map_err(RunError::Store)?;
+    }
+    dag.run_derivation(hash.clone())
+}
+
+/// `piper verify [hash...]`: compare entries (all finished ones if no
+/// hashes are given) with their manifests. With `invalidate`, corrupted
+/// entries are marked invalid so the next run rebuilds them.
+pub fn verify(
+    dag: &DerivationGraph,
+    hashes: &[String],
+    invalidate: bool,
+) -> Result<(), RunError> {
+    let store = Store::new(dag.config.work_dir());
+    let entries = if hashes.is_empty() {
+        store
+            .entries()
+            .map_err(RunError::Store)?
+            .into_iter()
+            .filter(|(_, entry)| entry.is_finished())
+            .collect()
+    } else {
+        hashes
+            .iter()
+            .map(|h| {
+                let hash = DerivationHash::from(h.as_str());
+                let entry = store.entry(&hash);
+                (hash, entry)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut corrupted = Vec::new();
+    for (hash, entry) in &entries {
+        match entry.verify() {
+            Ok(mismatches) if mismatches.is_empty() => {
+                println!("{}: ok", hash)
+            }
+            Ok(mismatches) => {
+                println!("{}: corrupted", hash);
+                for mismatch in mismatches {
+                    println!("  {}", mismatch);
+                }
+                corrupted.push(hash.clone());
+            }
+            Err(e) => {
+                println!("{}: couldn't be verified: {}", hash, e);
+                corrupted.push(hash.clone());
+            }
+        }
+    }
+    println!("verified {} entries", entries.len());
+    if corrupted.is_empty() {
+        return Ok(());
+    }
+    if invalidate {
+        for hash in &corrupted {
+            let entry = store.entry(hash);
+            if entry.path.exists() {
+                entry.set_status(Status::Invalid).map_err(RunError::Store)?;
+                println!("invalidated: {}", hash);
+            }
+        }
+    }
+    Err(RunError::Corrupted(corrupted))
+}
+
+/// `piper invalidate <hash or name>...`: mark derivations and everything
+/// depending on them invalid, so the next run rebuilds them. With `delete`,
+/// their entries are deleted instead.
+pub fn invalidate(
+    dag: &DerivationGraph,
+    targets: &[String],
+    delete: bool,
+) -> Result<(), RunError> {
+    let mut roots = Vec::new();
+    for target in targets {
+        let hash = DerivationHash::from(target.as_str());
+        if dag.get(&hash).is_some() {
+            roots.push(hash);
+            continue;
+        }
+        let named: Vec<DerivationHash> = dag
+            .nodes
+            .values()
+            .filter_map(|d| match d {
+                Derivation::Process(p) if p.has_name(target) => {
+                    Some(p.hash.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        if named.is_empty() {
+            return Err(RunError::NotInGraph(hash));
+        }
+        roots.extend(named);
+    }
+
+    let store = Store::new(dag.config.work_dir());
+    let mut hashes: Vec<DerivationHash> =
+        dag.dependents(roots).into_iter().collect();
+    hashes.sort_by_key(|h| h.to_string());
+    let mut count = 0;
+    for hash in &hashes {
+        let entry = store.entry(hash);
+        if !entry.path.exists() {
+            continue;
+        }
+        if delete {
+            store.remove(&entry).map_err(RunError::Store)?;
+            println!("deleted: {}", hash);
+        } else {
+            entry.set_status(Status::Invalid).map_err(RunError::Store)?;
+            println!("invalidated: {}", hash);
+        }
+        count += 1;
+    }
+    println!(
+        "{} {} entries of {} affected derivations",
+        if delete { "deleted" } else { "invalidated" },
+        count,
+        hashes.len()
+    );
+    Ok(())
+}
+
+/// `piper gc`: delete every entry that isn't needed by the pipeline, a
+/// pinned result or another running piper process
+pub fn gc(dag: &DerivationGraph, dry_run: bool) -> Result<(), RunError> {
+    let outputs = dag.outputs.clone().ok_or(RunError::NoOutputs)?.hash();
+    let roots = dag.closure(outputs)?.into_keys();
+    let store = Store::new(dag.config.work_dir());
+    if !dry_run {
+        store.recover().map_err(RunError::Store)?;
+    }
+    let live = store.live(roots).map_err(RunError::Store)?;
+    let collected = store
+        .collect_garbage(&live, dry_run)
+        .map_err(RunError::Store)?;
+    let action = if dry_run { "would remove" } else { "removed" };
+    for (hash, size) in &collected {
+        println!("{}: {} ({})", action, hash, human_size(*size));
+    }
+    let mut freed: u64 = collected.iter().map(|(_, size)| size).sum();
+    if !dry_run {
+        // blobs only used by the removed entries
+        let (_, bytes) = store.prune_links().map_err(RunError::Store)?;
+        freed += bytes;
+        store.prune_realisations().map_err(RunError::Store)?;
+        store.prune_file_hashes().map_err(RunError::Store)?;
+    }
+    println!(
+        "{} {} entries, {} {}",
+        action,
+        collected.len(),
+        human_size(freed),
+        if dry_run { "would be freed" } else { "freed" }
+    );
+    Ok(())
+}
+
+/// `piper optimise`: replace identical files in the store with hard links
+/// to a single copy
+pub fn optimise(dag: &DerivationGraph) -> Result<(), RunError> {
+    let store = Store::new(dag.config.work_dir());
+    let mut total = Savings::default();
+    for (hash, entry) in store.entries().map_err(RunError::Store)? {
+        let savings = store.optimise(&entry).map_err(RunError::Store)?;
+        if savings.linked_files > 0 {
+            println!(
+                "{}: linked {} files ({})",
+                hash,
+                savings.linked_files,
+                human_size(savings.bytes)
+            );
+        }
+        total.linked_files += savings.linked_files;
+        total.bytes += savings.bytes;
+    }
+    println!(
+        "linked {} files, {} freed",
+        total.linked_files,
+        human_size(total.bytes)
+    );
+    Ok(())
+}
+
+/// `piper pin <hash>...`: keep results and their inputs through `piper gc`
+pub fn pin(dag: &DerivationGraph, hashes: &[String]) -> Result<(), RunError> {
+    let store = Store::new(dag.config.work_dir());
+    for hash in hashes {
+        let hash = DerivationHash::from(hash.as_str());
+        if !store.entry(&hash).path.exists() {
+            println!("{}: not in the store (yet), pinning anyway", hash);
+        }
+        store.pin(&hash).map_err(RunError::Store)?;
+    }
+    Ok(())
+}
+
+/// `piper unpin <hash>...`
+pub fn unpin(dag: &DerivationGraph, hashes: &[String]) -> Result<(), RunError> {
+    let store = Store::new(dag.config.work_dir());
+    for hash in hashes {
+        let hash = DerivationHash::from(hash.as_str());
+        if !store.unpin(&hash).map_err(RunError::Store)? {
+            println!("{}: wasn't pinned", hash);
+        }
+    }
+    Ok(())
+}
+
+/// `bytes` in binary units, e.g. "1.5 GiB"
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
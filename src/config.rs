@@ -78,6 +78,10 @@ macro_rules! type_key {
 }
 
 
+/// Sizes in the config and process attributes (`memory`, `cacheMaxSize`,
+/// `cacheFilesizeCutoff`) are in megabytes
+pub static BYTES_PER_MB: u64 = 1024 * 1024;
+
 impl Config {
     /// Run the scheme config file in a steel vm, then extract the config struct and return
     pub fn new(config_path: Option<std::path::PathBuf>) -> Config {
@@ -143,6 +147,14 @@ impl Config {
             "workDir" => type_key!(value, ParamValue::String),
             "entryPoint" => type_key!(value, ParamValue::String),
             "shell" => type_key!(value, ParamValue::String),
+            "sandbox" => type_key!(value, ParamValue::Bool),
+            "localCpus" => type_key!(value, ParamValue::Int),
+            "localMemory" => type_key!(value, ParamValue::Int),
+            "cacheMaxSize" => type_key!(value, ParamValue::Int),
+            "autoOptimise" => type_key!(value, ParamValue::Bool),
+            "contentAddressed" => type_key!(value, ParamValue::Bool),
+            "hash-interpreter" => type_key!(value, ParamValue::Bool),
+            "cacheFilesizeCutoff" => type_key!(value, ParamValue::Int),
             _ => {}
         };
         self.config.insert(key, value);
@@ -159,6 +171,27 @@ impl Config {
         }
     }
 
+    /// A config item holding a non negative integer, if it is set
+    pub fn get_usize(&self, key: &str) -> Option<usize> {
+        match self.config.get(key) {
+            Some(ParamValue::Int(v)) => usize::try_from(*v).ok(),
+            _ => None,
+        }
+    }
+
+    /// A boolean config item, false if it isn't set
+    pub fn get_bool(&self, key: &str) -> bool {
+        matches!(self.config.get(key), Some(ParamValue::Bool(true)))
+    }
+
+    /// The directory derivations are stored in, see [`crate::store`]
+    pub fn work_dir(&self) -> String {
+        match self.config.get("workDir") {
+            Some(ParamValue::String(v)) => v.clone(),
+            _ => panic!("No workDir in config!"),
+        }
+    }
+
     /// The location of the entrypoint for the piper pipeline, e.g. "main.scm"
     /// The entrypoint should contain an outputs macro
     pub fn entry_point(&self) -> String{
@@ -53,6 +53,7 @@ impl DerivationGraph {
         derivation::file::register_steel_functions(&mut module)?;
         derivation::output::register_steel_functions(&mut module);
         derivation::dataframe::register_steel_functions(&mut module);
+        derivation::sources::register_steel_functions(&mut module);
         module.register_fn("node_count", DerivationGraph::node_count);
         module.register_fn("display_nodes", DerivationGraph::display_nodes);
         module.register_fn("add_output", DerivationGraph::add_outputs);
@@ -83,9 +84,28 @@ impl DerivationGraph {
         derivation: Derivation,
     ) -> Result<Derivation, InsertError<DerivationHash>> {
         let hash = derivation.hash();
+        // the name isn't hashed, so identical processes or fetches are the
+        // same node, known by every name they were given
+        if let Some(existing) = self.nodes.get_mut(&hash) {
+            if let (Derivation::Process(existing), Derivation::Process(new)) =
+                (&mut *existing, &derivation)
+            {
+                if !existing.has_name(&new.name) {
+                    existing.aliases.push(new.name.clone());
+                }
+            }
+            return Ok(existing.clone());
+        }
         self.nodes.safe_insert(hash, derivation.clone())
     }
 
+    /// Look up a derivation by hash, including the outputs derivation
+    pub fn get(&self, hash: &DerivationHash) -> Option<&Derivation> {
+        self.nodes
+            .get(hash)
+            .or(self.outputs.as_ref().filter(|o| o.hash() == *hash))
+    }
+
     pub fn node_count(&self) {
         println!("{}", self.nodes.len())
     }
@@ -131,3 +151,33 @@ impl<K: Eq + Hash, V: Clone> SafeInsert<K, V> for HashMap<K, V> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::engine;
+
+    #[test]
+    fn same_hash_keeps_every_name() {
+        let mut e = engine(None);
+        e.run(
+            r#"(define a (process! name : "a" script : "echo x > ${out}"))
+               (define b (process! name : "b" script : "echo x > ${out}"))
+               (define c (process! name : "a" script : "echo x > ${out}"))"#
+                .to_string(),
+        )
+        .expect("invalid pipeline");
+        let graph = extract_graph(&mut e).expect("no graph");
+        assert_eq!(graph.nodes.len(), 1);
+        let Some(Derivation::Process(process)) = graph.nodes.values().next()
+        else {
+            panic!("no process in the graph");
+        };
+        assert!(process.has_name("a") && process.has_name("b"));
+        let metadata = process.metadata(crate::store::Status::Running);
+        assert_eq!(
+            (metadata.name, metadata.aliases),
+            ("a".to_string(), vec!["b".to_string()])
+        );
+    }
+}
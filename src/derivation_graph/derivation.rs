@@ -6,7 +6,7 @@ use regex::bytes::Regex;
 use std::fmt::Display;
 use std::path::PathBuf;
 use std::{
-    collections::HashMap, hash::Hash,
+    collections::{BTreeMap, HashMap}, hash::Hash,
 };
 use steel::steel_vm::builtin::BuiltInModule;
 use steel::steel_vm::register_fn::RegisterFn;
@@ -16,12 +16,14 @@ use steel::{
 };
 
 use crate::config::{Config, ParamValue};
+pub mod canonical;
 pub mod evaluator;
 use sha2::Digest;
 pub mod dataframe;
 pub mod file;
 pub mod output;
 pub mod process;
+pub mod sources;
 pub mod iterator;
 pub mod test;
 use steel_derive::Steel;
@@ -117,6 +119,8 @@ impl steel::rvals::Custom for Derivation {
 pub struct File {
     pub path: PathBuf,
     pub hash: DerivationHash,
+    /// files of a `directory!`, relative to `path`, None for a single file
+    pub files: Option<Vec<PathBuf>>,
 }
 
 #[derive(Debug, Clone, Steel)]
@@ -150,15 +154,31 @@ pub struct Process {
     attributes: HashMap<String, SteelVal>,
     pub script: ScriptString,
     pub name: String,
+    /// names of other processes with the same hash, see
+    /// `DerivationGraph::add_derivation`
+    pub aliases: Vec<String>,
     pub hash: DerivationHash,
     pub inward_edges: Vec<DerivationHash>,
     pub container: Option<String>,
     pub time: Option<usize>,
     pub memory: Option<usize>,
+    pub cpus: Option<usize>,
     pub shell: String,
     pub hpc_runtime: Option<String>,
     pub container_runtime: Option<String>,
     pub work_dir: String,
+    pub sandbox: bool,
+    pub network: bool,
+    pub content_addressed: bool,
+    /// everything besides the script the hash depends on, see
+    /// `process::hash_inputs`
+    pub hash_inputs: BTreeMap<String, String>,
+    /// see `process::fixed_output_hash`
+    pub output_hash: Option<String>,
+    /// rerun on every run, e.g. a query of an external database
+    pub volatile: bool,
+    /// minutes after which a cached result is rerun
+    pub ttl: Option<usize>,
 }
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -169,6 +189,11 @@ impl std::fmt::Display for DerivationHash {
         write!(f, "{}", self.0)
     }
 }
+impl From<&str> for DerivationHash {
+    fn from(hash: &str) -> Self {
+        DerivationHash(hash.to_string())
+    }
+}
 impl Custom for DerivationHash {
     fn fmt(&self) -> Option<std::result::Result<String, std::fmt::Error>> {
         Some(Ok(self.0.clone()))
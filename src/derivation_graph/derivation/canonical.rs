@@ -0,0 +1,101 @@
+//! Canonical serialization every derivation hash is computed from.
+//!
+//! A hash starts with the hash scheme version and the kind of derivation,
+//! followed by length prefixed fields, so two different derivations never
+//! serialize to the same bytes and nothing depends on the iteration order of
+//! a `HashMap` or on a random seed. [`HASH_SCHEME`] has to be increased
+//! whenever the serialization of any derivation changes, the store records
+//! the version its entries were hashed with.
+use sha2::{Digest, Sha256};
+
+/// Version of the serialization below
+pub static HASH_SCHEME: u32 = 6;
+
+pub struct CanonicalHasher {
+    hasher: Sha256,
+}
+
+impl CanonicalHasher {
+    /// Start the hash of a derivation of type `kind`
+    pub fn new(kind: &str) -> Self {
+        let mut hasher = Self {
+            hasher: Sha256::new(),
+        };
+        hasher
+            .field(format!("piper-hash-{}", HASH_SCHEME))
+            .field(kind);
+        hasher
+    }
+
+    pub fn field(&mut self, bytes: impl AsRef<[u8]>) -> &mut Self {
+        let bytes = bytes.as_ref();
+        self.hasher.update((bytes.len() as u64).to_le_bytes());
+        self.hasher.update(bytes);
+        self
+    }
+
+    /// A field that may be missing, missing and empty fields differ
+    pub fn optional(&mut self, bytes: Option<impl AsRef<[u8]>>) -> &mut Self {
+        match bytes {
+            Some(bytes) => {
+                self.hasher.update([1]);
+                self.field(bytes)
+            }
+            None => {
+                self.hasher.update([0]);
+                self
+            }
+        }
+    }
+
+    /// Lowercase hex digest of everything written so far
+    pub fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(kind: &str, fields: &[&str]) -> String {
+        let mut hasher = CanonicalHasher::new(kind);
+        for field in fields {
+            hasher.field(field);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn same_fields_same_hash() {
+        assert_eq!(hash("process", &["a", "b"]), hash("process", &["a", "b"]));
+        assert_eq!(hash("process", &[]).len(), 64);
+    }
+
+    #[test]
+    fn fields_are_delimited() {
+        assert_ne!(
+            hash("process", &["ab", "c"]),
+            hash("process", &["a", "bc"])
+        );
+        assert_ne!(hash("process", &["a", ""]), hash("process", &["a"]));
+        assert_ne!(hash("process", &["a", "b"]), hash("process", &["b", "a"]));
+    }
+
+    #[test]
+    fn kinds_differ() {
+        assert_ne!(hash("process", &["a"]), hash("file", &["a"]));
+    }
+
+    #[test]
+    fn missing_and_empty_differ() {
+        let optional = |value: Option<&str>| {
+            let mut hasher = CanonicalHasher::new("process");
+            hasher.optional(value);
+            hasher.finish()
+        };
+        assert_ne!(optional(None), optional(Some("")));
+        assert_ne!(optional(Some("")), hash("process", &[""]));
+        assert_eq!(optional(Some("a")), optional(Some("a")));
+    }
+}
@@ -1,11 +1,9 @@
+use super::canonical::CanonicalHasher;
 use super::{Dataframe, DerivationHash, DisplayTable};
 use comfy_table::modifiers::UTF8_ROUND_CORNERS;
 use comfy_table::presets::UTF8_FULL;
 use comfy_table::{ContentArrangement, Table};
 use polars::prelude::*;
-use polars_utils::aliases::PlSeedableRandomStateQuality;
-use polars_utils::total_ord::TotalHash;
-use sha2::Digest;
 use steel::SteelErr;
 use steel::SteelVal;
 use steel::steel_vm::builtin::BuiltInModule;
@@ -44,23 +42,43 @@ impl Dataframe {
         .hash()
     }
 
+    /// A frame with the `path` of every file, which depends on the files,
+    /// e.g. from `glob!`
+    pub fn from_files(
+        files: Vec<super::Derivation>,
+    ) -> Result<Dataframe, String> {
+        let mut paths = Vec::new();
+        let mut derivations = Vec::new();
+        for derivation in files {
+            let file = match derivation {
+                super::Derivation::File(file) => file,
+                other => return Err(format!("{} is not a file", other.hash())),
+            };
+            paths.push(file.path.to_string_lossy().into_owned());
+            derivations.push(file.hash);
+        }
+        let column = Column::from(Series::new("path".into(), paths));
+        Self {
+            frame: DataFrame::new_infer_height(vec![column])
+                .map_err(|x| x.to_string())?,
+            hash: DerivationHash::default(),
+            derivations,
+        }
+        .hash()
+    }
+
     pub fn into_derivation(self) -> super::Derivation {
         super::Derivation::Dataframe(self)
     }
 
     pub fn hash(mut self) -> Result<Dataframe, String> {
-        let mut hasher = sha2::Sha256::new();
-        let frame_hash = hash_frame(&self.frame)?.0;
-        hasher.update(frame_hash);
-        hasher.update(
-            self.derivations
-                .iter()
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>()
-                .join(""),
-        );
-        let result = hasher.finalize();
-        self.hash = DerivationHash(format!("{:x}", result));
+        let mut hasher = CanonicalHasher::new("dataframe");
+        hash_frame(&mut hasher, &self.frame)?;
+        hasher.field(self.derivations.len().to_string());
+        for derivation in &self.derivations {
+            hasher.field(derivation.to_string());
+        }
+        self.hash = DerivationHash(hasher.finish());
         Ok(self)
     }
 
@@ -124,6 +142,7 @@ impl Dataframe {
 
 pub fn register_steel_functions(module: &mut BuiltInModule) {
     module.register_fn("read-csv", Dataframe::read_csv);
+    module.register_fn("Dataframe::from_files", Dataframe::from_files);
     module.register_fn("with-column", Dataframe::with_column);
     module
         .register_fn("Dataframe::into_derivation", Dataframe::into_derivation);
@@ -442,25 +461,69 @@ fn test_polars() {
     let first = iter.next().expect("a key");
 }
 
-fn hash_frame(frame: &DataFrame) -> Result<DerivationHash, String> {
-    let mut columns = frame.columns().iter();
-    let first = columns.next().ok_or_else(|| {
-        "At least one column must exist for hashing".to_string()
-    })?;
-    let hasher = PlSeedableRandomStateQuality::default();
-    let mut hashes = Vec::<u64>::new();
-
-    for col in columns {
-        col.vec_hash_combine(hasher.clone(), &mut hashes);
+/// Add the height of `frame` and the name, type and values of every column
+/// to `hasher`, in column order
+fn hash_frame(
+    hasher: &mut CanonicalHasher,
+    frame: &DataFrame,
+) -> Result<(), String> {
+    if frame.columns().is_empty() {
+        return Err("At least one column must exist for hashing".to_string());
     }
+    hasher.field(frame.height().to_string());
+    for col in frame.columns() {
+        hasher
+            .field(col.name().as_str())
+            .field(col.dtype().to_string());
+        for i in 0..col.len() {
+            let value = col.get(i).map_err(|x| x.to_string())?;
+            hasher.optional(value_bytes(&value)?);
+        }
+    }
+    Ok(())
+}
 
-    Ok(DerivationHash(
-        hashes
-            .iter()
-            .map(|x| x.to_string())
-            .collect::<Vec<String>>()
-            .join(""),
-    ))
+/// Canonical encoding of a value, None for nulls. Numbers are written as
+/// little endian bytes and every NaN the same way; the type is hashed with
+/// the column, so it isn't repeated here.
+fn value_bytes(value: &AnyValue) -> Result<Option<Vec<u8>>, String> {
+    let bytes = match value {
+        AnyValue::Null => return Ok(None),
+        AnyValue::Boolean(v) => vec![u8::from(*v)],
+        AnyValue::UInt8(v) => v.to_le_bytes().to_vec(),
+        AnyValue::UInt16(v) => v.to_le_bytes().to_vec(),
+        AnyValue::UInt32(v) => v.to_le_bytes().to_vec(),
+        AnyValue::UInt64(v) => v.to_le_bytes().to_vec(),
+        AnyValue::Int8(v) => v.to_le_bytes().to_vec(),
+        AnyValue::Int16(v) => v.to_le_bytes().to_vec(),
+        AnyValue::Int32(v) => v.to_le_bytes().to_vec(),
+        AnyValue::Int64(v) => v.to_le_bytes().to_vec(),
+        AnyValue::Float32(v) => {
+            let v = if v.is_nan() { f32::NAN } else { *v };
+            v.to_bits().to_le_bytes().to_vec()
+        }
+        AnyValue::Float64(v) => {
+            let v = if v.is_nan() { f64::NAN } else { *v };
+            v.to_bits().to_le_bytes().to_vec()
+        }
+        AnyValue::String(v) => v.as_bytes().to_vec(),
+        AnyValue::StringOwned(v) => v.as_bytes().to_vec(),
+        AnyValue::Binary(v) => v.to_vec(),
+        AnyValue::BinaryOwned(v) => v.clone(),
+        AnyValue::List(series) => {
+            let mut nested = CanonicalHasher::new("list");
+            nested.field(series.len().to_string());
+            for i in 0..series.len() {
+                let value = series.get(i).map_err(|x| x.to_string())?;
+                nested.optional(value_bytes(&value)?);
+            }
+            nested.finish().into_bytes()
+        }
+        // derivations in a column, e.g. from `glob!`
+        AnyValue::Object(v) => v.to_string().into_bytes(),
+        v => return Err(format!("Can't hash values of type {}", v.dtype())),
+    };
+    Ok(Some(bytes))
 }
 
 // Stuff needed for custom types in polars
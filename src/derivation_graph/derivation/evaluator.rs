@@ -1,62 +1,44 @@
 use std::{
-    borrow::BorrowMut,
     fs,
     io::Write,
-    process::{Command, ExitStatus},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    time::{Duration, Instant},
 };
 
 use enum_dispatch::enum_dispatch;
+pub mod limits;
+pub mod sandbox;
+pub mod usage;
 
-enum CacheState {
-    Valid,
-    Invalid,
-}
+use crate::store::{Status, Store, StoreEntry, remove_tree};
 
-fn make_dir_check_hash(work_dir: String) -> CacheState {
-    match fs::create_dir_all(work_dir.clone()) {
-        Ok(v) => CacheState::Invalid, // should check for completeness as well
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::PermissionDenied => {
-                panic!("Cannot create {}, permission denied", work_dir.clone())
-            }
-            std::io::ErrorKind::AlreadyExists => {
-                if std::path::PathBuf::from(format!("{}/.finished", work_dir))
-                    .exists()
-                {
-                    CacheState::Valid
-                } else {
-                    CacheState::Invalid
-                }
-            } // this is broekn with create_dir_all i think,
-            v => {
-                panic!("Couldn't create {} for reason: {}", work_dir, v)
-            }
-        },
-    }
+/// Create the run and log directories of an entry and record that it is
+/// running
+fn prepare_entry(
+    entry: &StoreEntry,
+    derivation: &super::Process,
+) -> std::io::Result<()> {
+    fs::create_dir_all(entry.run_dir())?;
+    fs::create_dir_all(entry.logs_dir())?;
+    entry.write_metadata(&derivation.metadata(Status::Running))
 }
 
 fn symlink_edges(
     edges: Vec<super::DerivationHash>,
-    all_work_dir: String,
-    work_dir: String,
+    store: &Store,
+    work_dir: &std::path::Path,
 ) -> std::io::Result<()> {
-    if edges.is_empty() {
-        return Ok(());
-    } else {
-        for i in edges {
-
-            let symlink = std::os::unix::fs::symlink(
-                std::path::absolute(format!("{}/{}/out", all_work_dir, i))
-                    .expect("couldn't resolve absolute path"),
-                std::path::absolute(format!("{}/{}", work_dir, i))
-                    .expect("couldn't resolve absolute path"),
-            );
-            if let Err(e) = symlink {
-                match e.kind() {
-                    std::io::ErrorKind::AlreadyExists => {
-                    },
-                    _ => {return Err(e)}
-                }
+    for i in edges {
+        let symlink = std::os::unix::fs::symlink(
+            std::path::absolute(store.entry(&i).out())?,
+            work_dir.join(i.to_string()),
+        );
+        if let Err(e) = symlink {
+            match e.kind() {
+                std::io::ErrorKind::AlreadyExists => {}
+                _ => return Err(e),
             }
         }
     }
@@ -65,25 +47,41 @@ fn symlink_edges(
 }
 
 pub fn run_derivation(derivation: &super::Process) -> Option<HPCRuntime> {
-    
-    
-    let work_dir = format!(
-        "{}/{:?}/run",
-        derivation.work_dir.clone(),
-        derivation.hash
-    );
+    let store = Store::new(&derivation.work_dir);
+    let cached = store.entry(&derivation.hash);
+    if cached.is_finished() {
+        // only used to pick what is evicted first, see `cacheMaxSize`
+        let _ = cached.touch();
+        return None;
+    }
 
-    let cache_state = make_dir_check_hash(work_dir.clone());
-    symlink_edges(
-        derivation.inward_edges.clone(),
-        derivation.work_dir.clone(),
-        work_dir.clone(),
-    )
-    .expect("couldn't create symlinks");
+    // built on the side and moved into the store once it stopped, see
+    // `Store::commit`
+    Some(start(
+        derivation,
+        &store,
+        &store.build_entry(&derivation.hash),
+    ))
+}
 
-    if let CacheState::Valid = cache_state {
-        return None;
+/// Run `derivation` in `entry`, whatever is in the store already
+pub fn start(
+    derivation: &super::Process,
+    store: &Store,
+    entry: &StoreEntry,
+) -> HPCRuntime {
+    if entry.path.exists() {
+        remove_tree(&entry.path).unwrap_or_else(|e| {
+            panic!("Couldn't remove {}: {}", entry.path.display(), e)
+        });
     }
+    prepare_entry(entry, derivation).unwrap_or_else(|e| {
+        panic!("Couldn't create {}: {}", entry.path.display(), e)
+    });
+    let work_dir = entry.run_dir();
+    symlink_edges(derivation.inward_edges.clone(), store, &work_dir)
+        .expect("couldn't create symlinks");
+
     let container_runtime: ContainerRuntime;
 
     if derivation.container.is_none() || derivation.container_runtime.is_none()
@@ -95,10 +93,31 @@ pub fn run_derivation(derivation: &super::Process) -> Option<HPCRuntime> {
     }
 
     let mut cmd = derivation.script();
-    let mut hpc_r = NoHPCRuntime::new();
+    let mut hpc_r = NoHPCRuntime::new()
+        .with_limits(limits::Limits {
+            time: derivation.time,
+            memory: derivation.memory,
+        })
+        .with_logs(entry.stdout(), entry.stderr());
+    if derivation.sandbox {
+        hpc_r = hpc_r.with_sandbox(sandbox::Sandbox::new(
+            store.sandbox_dir(&derivation.hash),
+            entry.path.clone(),
+            derivation
+                .inward_edges
+                .iter()
+                .map(|i| store.entry(i).out())
+                .collect(),
+            derivation.network,
+        ));
+    }
     cmd = container_runtime.cmd(cmd);
-    hpc_r.submit_job(derivation.shell.clone(), cmd, work_dir.clone());
-    Some(HPCRuntime::from(hpc_r))
+    hpc_r.submit_job(
+        derivation.shell.clone(),
+        cmd,
+        work_dir.to_string_lossy().to_string(),
+    );
+    HPCRuntime::from(hpc_r)
 }
 
 #[enum_dispatch(HPCRuntimeFunctions)]
@@ -110,17 +129,95 @@ pub enum HPCRuntime {
 pub trait HPCRuntimeFunctions {
     fn submit_job(&mut self, shell: String, cmd: String, work_dir: String);
     fn cmd(&self, cmd: String) -> String;
-    fn wait(&mut self) -> Option<ExitStatus>;
+    fn wait(&mut self) -> Option<Result<ExitStatus, JobError>>;
     fn finished(&mut self) -> bool;
+    /// resources used by a finished job, if the runtime can measure them
+    fn usage(&self) -> Option<usage::ResourceUsage>;
+    /// how a finished job exited, if the runtime knows
+    fn exit_status(&self) -> Option<ExitStatus>;
+    /// forward `signal` to the job, or cancel it with the scheduler
+    fn cancel(&mut self, signal: i32);
+}
+
+/// Reasons a job did not finish successfully
+#[derive(Debug)]
+pub enum JobError {
+    /// killed after running longer than its `time` (minutes)
+    Timeout(usize),
+    /// killed for using more than its `memory` (megabytes)
+    OutOfMemory(usize),
+    /// exited on its own with a failing status
+    Failed(ExitStatus),
+    /// stopped because piper received this signal
+    Cancelled(i32),
+    /// the job's logs, limits or sandbox couldn't be set up
+    NotStarted(String),
 }
 
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::Timeout(v) => {
+                write!(f, "killed after exceeding its time of {} minutes", v)
+            }
+            JobError::OutOfMemory(v) => {
+                write!(f, "killed after exceeding its memory of {} MB", v)
+            }
+            JobError::Failed(v) => write!(f, "failed with {}", v),
+            JobError::Cancelled(v) => write!(f, "cancelled by signal {}", v),
+            JobError::NotStarted(v) => write!(f, "couldn't start: {}", v),
+        }
+    }
+}
+
+/// How often the walltime of a running job is checked
+static POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct NoHPCRuntime {
     childprocess: Option<std::process::Child>,
+    sandbox: Option<sandbox::Sandbox>,
+    limits: limits::Limits,
+    cgroup: Option<limits::Cgroup>,
+    started: Option<Instant>,
+    timed_out: bool,
+    cancelled: Option<i32>,
+    logs: Option<(PathBuf, PathBuf)>,
+    exit: Option<(ExitStatus, usage::ResourceUsage)>,
+    not_started: Option<String>,
 }
 
 impl NoHPCRuntime {
     fn new() -> Self {
-        Self { childprocess: None }
+        Self {
+            childprocess: None,
+            sandbox: None,
+            limits: limits::Limits::default(),
+            cgroup: None,
+            started: None,
+            timed_out: false,
+            cancelled: None,
+            logs: None,
+            exit: None,
+            not_started: None,
+        }
+    }
+
+    /// Enforce the `time` and `memory` of the process, see [`limits`]
+    fn with_limits(mut self, limits: limits::Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Write the job's stdout and stderr to these files
+    fn with_logs(mut self, stdout: PathBuf, stderr: PathBuf) -> Self {
+        self.logs = Some((stdout, stderr));
+        self
+    }
+
+    /// Run the job inside of a namespace sandbox, see [`sandbox`]
+    fn with_sandbox(mut self, sandbox: sandbox::Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
     }
 }
 
@@ -135,43 +232,188 @@ impl HPCRuntimeFunctions for NoHPCRuntime {
         let cmd = self.cmd(cmd);
         write_command_to_file(cmd.clone(), work_dir.clone())
             .expect("couldn't write cmd to file");
-        let mut child = Command::new("sh");
-        self.childprocess = Some(
-            child
-                .arg(".cmd")
-                .current_dir(work_dir)
-                .spawn()
-                .unwrap_or_else(|e| {
-                    panic!(
-                        "couldn't start process: {} because of: {}",
-                        cmd.clone(),
-                        e
-                    )
-                }),
-        );
+        match self.spawn(Path::new(&work_dir)) {
+            Ok(child) => {
+                self.childprocess = Some(child);
+                self.started = Some(Instant::now());
+            }
+            // reported by `wait`, so the derivation is recorded as failed
+            Err(e) => {
+                self.not_started = Some(e);
+                self.remove_sandbox_root();
+            }
+        }
     }
     fn cmd(&self, cmd: String) -> String {
         cmd
     }
-    fn wait(&mut self) -> Option<ExitStatus> {
-        Some(
-            self.childprocess
-                .take()?
-                .wait()
-                .expect("failed to wait for job"),
-        )
+    fn wait(&mut self) -> Option<Result<ExitStatus, JobError>> {
+        if let Some(e) = &self.not_started {
+            return Some(Err(JobError::NotStarted(e.clone())));
+        }
+        self.childprocess.as_ref()?;
+        while !self.finished() {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        // reaped by `finished`, dropping the child doesn't wait for it again
+        self.childprocess = None;
+        let (status, _) = self.exit?;
+        Some(self.outcome(status))
     }
     fn finished(&mut self) -> bool {
-        if let Some(c) = self.childprocess.borrow_mut() {
-            match c.try_wait() {
-                Ok(Some(status)) => true,
-                Ok(None) => false,
-                Err(e) => panic!("failed to check job status"),
+        if self.exit.is_some() {
+            return true;
+        }
+        let Some(pid) = self.childprocess.as_ref().map(|c| c.id()) else {
+            return true; // not running (anymore)
+        };
+        if self.over_time() {
+            self.kill();
+        }
+        self.reap(pid as libc::pid_t)
+    }
+    fn usage(&self) -> Option<usage::ResourceUsage> {
+        self.exit.map(|(_, usage)| usage)
+    }
+    fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit.map(|(status, _)| status)
+    }
+    fn cancel(&mut self, signal: i32) {
+        let Some(child) = &self.childprocess else {
+            return;
+        };
+        if signal == libc::SIGKILL {
+            if let Some(cgroup) = &self.cgroup {
+                cgroup.kill();
+            }
+        }
+        // once reaped, the id of its process group may have been reused, so
+        // what a job left behind can only be killed through its cgroup
+        if self.exit.is_some() {
+            return;
+        }
+        // the first signal is the one that is reported
+        self.cancelled.get_or_insert(signal);
+        // SAFETY: the job was started in a process group named after its pid
+        // and hasn't been reaped
+        unsafe { libc::killpg(child.id() as libc::pid_t, signal) };
+    }
+}
+
+impl NoHPCRuntime {
+    /// Start `.cmd` in `work_dir` with the logs, limits and sandbox of the
+    /// job
+    fn spawn(&mut self, work_dir: &Path) -> Result<Child, String> {
+        let mut child = Command::new("sh");
+        // own process group, so the whole job can be killed at once
+        child.arg(".cmd").current_dir(work_dir).process_group(0);
+        if let Some((stdout, stderr)) = &self.logs {
+            let open = |path: &PathBuf| {
+                fs::File::create(path).map_err(|e| {
+                    format!("couldn't create {}: {}", path.display(), e)
+                })
+            };
+            child.stdout(open(stdout)?).stderr(open(stderr)?);
+        }
+        // the cgroup has to be joined before the sandbox's user namespace
+        self.cgroup = self
+            .limits
+            .apply(&mut child)
+            .map_err(|e| format!("couldn't limit memory: {}", e))?;
+        if let Some(sandbox) = &self.sandbox {
+            sandbox
+                .apply(&mut child, work_dir)
+                .map_err(|e| format!("couldn't set up the sandbox: {}", e))?;
+        }
+        // errors of the limits and sandbox inside the child end up here too
+        child
+            .spawn()
+            .map_err(|e| format!("couldn't run .cmd: {}", e))
+    }
+
+    /// Collect the job and its resource usage if it exited
+    fn reap(&mut self, pid: libc::pid_t) -> bool {
+        let mut status = 0;
+        // SAFETY: wait4 only writes into the zeroed struct
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        let reaped = unsafe {
+            libc::wait4(pid, &mut status, libc::WNOHANG, &mut rusage)
+        };
+        match reaped {
+            0 => false,
+            -1 => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    return false;
+                }
+                panic!("failed to check job status: {}", e)
+            }
+            _ => {
+                let usage = usage::ResourceUsage::new(
+                    &rusage,
+                    self.started.map(|s| s.elapsed()).unwrap_or_default(),
+                    self.cgroup.as_ref(),
+                );
+                self.exit = Some((ExitStatus::from_raw(status), usage));
+                self.remove_sandbox_root();
+                true
+            }
+        }
+    }
+
+    fn remove_sandbox_root(&self) {
+        if let Some(sandbox) = &self.sandbox {
+            if let Err(e) = sandbox.remove_root() {
+                println!("couldn't remove the sandbox root: {}", e);
             }
-        } else {
-            false // hasn't started yet
         }
     }
+
+    fn over_time(&self) -> bool {
+        match (self.started, self.limits.walltime()) {
+            (Some(started), Some(walltime)) => started.elapsed() > walltime,
+            _ => false,
+        }
+    }
+
+    /// Kill the job's process group (and cgroup, which also catches
+    /// processes that moved to their own process group)
+    fn kill(&mut self) {
+        let Some(child) = &self.childprocess else {
+            return;
+        };
+        if self.timed_out || self.exit.is_some() {
+            return;
+        }
+        self.timed_out = true;
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.kill();
+        }
+        // SAFETY: the job was started in a process group named after its pid
+        unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) };
+    }
+
+    fn outcome(&mut self, status: ExitStatus) -> Result<ExitStatus, JobError> {
+        // dropping the cgroup removes it, it is empty now
+        let cgroup = self.cgroup.take();
+        if self.timed_out {
+            return Err(JobError::Timeout(
+                self.limits.time.unwrap_or_default(),
+            ));
+        }
+        if let Some(signal) = self.cancelled {
+            return Err(JobError::Cancelled(signal));
+        }
+        if status.success() {
+            return Ok(status);
+        }
+        if cgroup.is_some_and(|c| c.oom_killed()) {
+            return Err(JobError::OutOfMemory(
+                self.limits.memory.unwrap_or_default(),
+            ));
+        }
+        Err(JobError::Failed(status))
+    }
 }
 
 pub struct LsfHPCRuntime {}
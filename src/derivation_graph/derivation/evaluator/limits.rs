@@ -0,0 +1,178 @@
+//! Enforcement of the `time` and `memory` attributes for the local runtime.
+//!
+//! Schedulers enforce these limits themselves, but without one piper has to.
+//! Memory is limited with a cgroup v2 group when the memory controller is
+//! delegated to piper, falling back to `RLIMIT_AS` otherwise. Only the cgroup
+//! can tell an out of memory kill apart from other failures. `RLIMIT_AS`
+//! caps the virtual address space of each process rather than the memory it
+//! uses: programs reserving much more than they touch, like the JVM or Go
+//! binaries, fail to start or allocate well below `memory`, and children
+//! each get the full limit. Time is enforced by the runtime, which kills the
+//! job's process group once it runs too long.
+use crate::config::BYTES_PER_MB;
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+static CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Distinguishes the cgroups of jobs started by this piper process
+static CGROUP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Resource limits of a single job
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    /// walltime in minutes
+    pub time: Option<usize>,
+    /// memory in megabytes
+    pub memory: Option<usize>,
+}
+
+impl Limits {
+    pub fn walltime(&self) -> Option<Duration> {
+        self.time.map(|t| Duration::from_secs(t as u64 * 60))
+    }
+
+    /// Make `cmd` run under the memory limit, returns the cgroup of the job
+    /// if one could be created.
+    pub fn apply(&self, cmd: &mut Command) -> io::Result<Option<Cgroup>> {
+        let Some(memory) = self.memory else {
+            return Ok(None);
+        };
+        let bytes = memory as u64 * BYTES_PER_MB;
+        if let Ok(cgroup) = Cgroup::create(bytes) {
+            let procs = cgroup.procs()?;
+            // SAFETY: only writes to a file descriptor opened before the fork
+            unsafe {
+                cmd.pre_exec(move || {
+                    // "0" moves the writing process into the cgroup
+                    write_fd(procs.as_raw_fd(), b"0")
+                });
+            }
+            return Ok(Some(cgroup));
+        }
+        // caps virtual memory, not what the job actually uses, see above
+        // SAFETY: setrlimit is async-signal-safe
+        unsafe {
+            cmd.pre_exec(move || {
+                let limit = libc::rlimit {
+                    rlim_cur: bytes,
+                    rlim_max: bytes,
+                };
+                if libc::setrlimit(libc::RLIMIT_AS, &limit) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(None)
+    }
+}
+
+fn write_fd(fd: libc::c_int, contents: &[u8]) -> io::Result<()> {
+    if unsafe { libc::write(fd, contents.as_ptr().cast(), contents.len()) }
+        == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A cgroup v2 group holding a single job, removed when dropped
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create a group limited to `memory` bytes next to or below the group
+    /// piper runs in, whichever has the memory controller delegated.
+    fn create(memory: u64) -> io::Result<Self> {
+        let own = fs::read_to_string("/proc/self/cgroup")?;
+        let own = own
+            .lines()
+            .find_map(|l| l.strip_prefix("0::"))
+            .ok_or_else(|| unsupported("cgroup v2 is not available"))?;
+        let own = Path::new(CGROUP_ROOT).join(own.trim_start_matches('/'));
+        let parent = own
+            .ancestors()
+            .take(2)
+            .find(|p| has_memory_controller(p))
+            .ok_or_else(|| {
+                unsupported("the memory controller is not delegated to piper")
+            })?;
+
+        let path = parent.join(format!(
+            "piper-{}-{}",
+            std::process::id(),
+            CGROUP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&path)?;
+        let cgroup = Cgroup { path };
+        fs::write(cgroup.path.join("memory.max"), memory.to_string())?;
+        // without this the job swaps instead of being killed
+        let _ = fs::write(cgroup.path.join("memory.swap.max"), "0");
+        Ok(cgroup)
+    }
+
+    fn procs(&self) -> io::Result<fs::File> {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.procs"))
+    }
+
+    /// true if the kernel killed a process of the job for exceeding memory.max
+    pub fn oom_killed(&self) -> bool {
+        fs::read_to_string(self.path.join("memory.events"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.strip_prefix("oom_kill "))
+            .any(|n| n.trim().parse::<u64>().unwrap_or(0) > 0)
+    }
+
+    /// Highest memory use of the group in bytes
+    pub fn peak_memory(&self) -> Option<u64> {
+        fs::read_to_string(self.path.join("memory.peak"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// Bytes read and written by the group, summed over all devices
+    pub fn io_bytes(&self) -> Option<(u64, u64)> {
+        let stat = fs::read_to_string(self.path.join("io.stat")).ok()?;
+        let field = |name: &str| -> u64 {
+            stat.split_whitespace()
+                .filter_map(|f| f.strip_prefix(name))
+                .filter_map(|v| v.parse::<u64>().ok())
+                .sum()
+        };
+        Some((field("rbytes="), field("wbytes=")))
+    }
+
+    /// Kill every process in the group, including ones that left the job's
+    /// process group
+    pub fn kill(&self) {
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+fn unsupported(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+fn has_memory_controller(cgroup: &Path) -> bool {
+    fs::read_to_string(cgroup.join("cgroup.subtree_control"))
+        .is_ok_and(|c| c.split_whitespace().any(|c| c == "memory"))
+}
@@ -0,0 +1,537 @@
+//! Linux namespace sandbox for the local runtime.
+//!
+//! When `sandbox` is enabled, each `.cmd` is run inside new user, mount, PID
+//! and (unless the process sets `network : #t`) network namespaces. The root
+//! of the sandbox is an empty tmpfs which only contains read-only system
+//! directories, the store entry of the derivation being run and the `out`
+//! paths of its inward edges (read-only). Absolute symlinks in those, like
+//! the ones to files above `cacheFilesizeCutoff`, are followed and their
+//! targets bound read-only as well. Scripts that read files which are not
+//! part of the graph fail instead of silently breaking the cache.
+//!
+//! This is meant to catch mistakes, not to contain hostile code.
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Host directories needed to run a shell and typical tools, bound read-only
+static SYSTEM_DIRS: [&str; 9] = [
+    "/bin",
+    "/sbin",
+    "/usr",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/etc",
+    "/opt",
+    "/nix/store",
+];
+
+/// Description of what a process is allowed to see
+pub struct Sandbox {
+    root: PathBuf,
+    entry_dir: PathBuf,
+    inputs: Vec<PathBuf>,
+    network: bool,
+}
+
+impl Sandbox {
+    /// `root` is an empty directory the sandbox root is mounted on,
+    /// `entry_dir` is the writable store entry of the derivation and
+    /// `inputs` are the `out` paths of its inward edges.
+    pub fn new(
+        root: PathBuf,
+        entry_dir: PathBuf,
+        inputs: Vec<PathBuf>,
+        network: bool,
+    ) -> Self {
+        Self {
+            root,
+            entry_dir,
+            inputs,
+            network,
+        }
+    }
+
+    /// Make `cmd` enter the sandbox before it is executed
+    pub fn apply(&self, cmd: &mut Command, cwd: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+        let prepared = self.prepare(cwd)?;
+        // SAFETY: `Prepared::enter` only performs raw syscalls on buffers
+        // allocated before the fork.
+        unsafe {
+            cmd.pre_exec(move || prepared.enter());
+        }
+        Ok(())
+    }
+
+    /// Remove the mount point of the root once the job exited, what was
+    /// mounted on it disappeared with the job's mount namespace
+    pub fn remove_root(&self) -> io::Result<()> {
+        match std::fs::remove_dir(&self.root) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Resolve every path and string needed by the forked child up front
+    fn prepare(&self, cwd: &Path) -> io::Result<Prepared> {
+        let root = std::path::absolute(&self.root)?;
+        let mut binds: Vec<(PathBuf, bool)> = SYSTEM_DIRS
+            .iter()
+            .map(PathBuf::from)
+            .filter(|p| p.exists())
+            .map(|p| (p, true))
+            .collect();
+        binds.push((PathBuf::from("/dev"), false));
+        binds.push((std::path::absolute(&self.entry_dir)?, false));
+        let mut originals = Vec::new();
+        for input in &self.inputs {
+            // inputs that were never built can't be bound, the script will
+            // fail to find them just like it would outside of the sandbox
+            if input.exists() {
+                binds.push((std::path::absolute(input)?, true));
+                link_targets(input, &mut originals)?;
+            }
+        }
+        // e.g. files above `cacheFilesizeCutoff`, which are symlinks to the
+        // originals, they'd be writable through the link otherwise
+        originals.sort();
+        originals.dedup();
+        binds.extend(originals.into_iter().map(|o| (o, true)));
+
+        let mut dirs = vec![root.join("proc"), root.join("tmp")];
+        let mut prepared_binds = Vec::new();
+        for (source, read_only) in binds {
+            let target = inside(&root, &source);
+            let is_dir = source.is_dir();
+            let parent = if is_dir {
+                Some(target.as_path())
+            } else {
+                target.parent()
+            };
+            if let Some(parent) = parent {
+                dirs.extend(
+                    parent
+                        .ancestors()
+                        .take_while(|p| p.starts_with(&root) && *p != root)
+                        .map(Path::to_path_buf),
+                );
+            }
+            let source = cstring(&source)?;
+            prepared_binds.push(PreparedBind {
+                locked_flags: locked_flags(&source),
+                source,
+                target: cstring(&target)?,
+                is_dir,
+                read_only,
+            });
+        }
+        // parents must be created before their children
+        dirs.sort();
+        dirs.dedup();
+
+        let mut clone_flags =
+            libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+        if !self.network {
+            clone_flags |= libc::CLONE_NEWNET;
+        }
+        // SAFETY: getuid and getgid can't fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        Ok(Prepared {
+            clone_flags,
+            uid_map: format!("{} {} 1", uid, uid).into_bytes(),
+            gid_map: format!("{} {} 1", gid, gid).into_bytes(),
+            proc: cstring(&root.join("proc"))?,
+            tmp: cstring(&root.join("tmp"))?,
+            cwd: cstring(&std::path::absolute(cwd)?)?,
+            dirs: dirs
+                .iter()
+                .map(|d| cstring(d))
+                .collect::<io::Result<Vec<CString>>>()?,
+            binds: prepared_binds,
+            root: cstring(&root)?,
+        })
+    }
+}
+
+/// Path of `path` once the sandbox root is mounted on `root`
+/// Add the absolute targets of the symlinks in `path` to `targets`
+fn link_targets(path: &Path, targets: &mut Vec<PathBuf>) -> io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        if target.is_absolute() && target.exists() {
+            targets.push(target);
+        }
+    } else if metadata.is_dir() {
+        for child in std::fs::read_dir(path)? {
+            link_targets(&child?.path(), targets)?;
+        }
+    }
+    Ok(())
+}
+
+fn inside(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Mount flags of `path` that can't be cleared from inside a user namespace,
+/// they have to be repeated when remounting a bind read-only
+fn locked_flags(path: &CStr) -> libc::c_ulong {
+    // SAFETY: statvfs only writes into the zeroed struct
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return 0;
+    }
+    [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+    ]
+    .iter()
+    .filter(|(st, _)| stat.f_flag & st != 0)
+    .fold(0, |flags, (_, ms)| flags | ms)
+}
+
+struct PreparedBind {
+    source: CString,
+    target: CString,
+    is_dir: bool,
+    read_only: bool,
+    locked_flags: libc::c_ulong,
+}
+
+/// Everything the child needs to enter the sandbox, allocated before the fork
+struct Prepared {
+    clone_flags: libc::c_int,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    root: CString,
+    proc: CString,
+    tmp: CString,
+    cwd: CString,
+    dirs: Vec<CString>,
+    binds: Vec<PreparedBind>,
+}
+
+impl Prepared {
+    /// Runs in the forked child right before `exec`. Only raw syscalls are
+    /// used here, allocating after a fork is not safe.
+    fn enter(&self) -> io::Result<()> {
+        check(unsafe { libc::unshare(self.clone_flags) })?;
+        write_file(c"/proc/self/setgroups", b"deny")?;
+        write_file(c"/proc/self/uid_map", &self.uid_map)?;
+        write_file(c"/proc/self/gid_map", &self.gid_map)?;
+
+        // only children of this process end up in the new PID namespace, so
+        // fork once more and let this process wait for the job
+        let pid = check(unsafe { libc::fork() })?;
+        if pid != 0 {
+            // `spawn` returns once std's pipe reporting whether `exec`
+            // succeeded is closed, which this process never execs to do
+            close_fds_from(3);
+            unsafe { libc::_exit(wait_for(pid)) }
+        }
+        check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })?;
+
+        self.mount_root()?;
+        check(unsafe { libc::chroot(self.root.as_ptr()) })?;
+        check(unsafe { libc::chdir(self.cwd.as_ptr()) })?;
+        Ok(())
+    }
+
+    fn mount_root(&self) -> io::Result<()> {
+        mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE)?;
+        mount(
+            Some(c"tmpfs"),
+            &self.root,
+            Some(c"tmpfs"),
+            libc::MS_NOSUID | libc::MS_NODEV,
+        )?;
+        // mounted first so binds below /tmp (e.g. a workDir there) stay visible
+        mkdir(&self.tmp)?;
+        mount(
+            Some(c"tmpfs"),
+            &self.tmp,
+            Some(c"tmpfs"),
+            libc::MS_NOSUID | libc::MS_NODEV,
+        )?;
+        for dir in &self.dirs {
+            mkdir(dir)?;
+        }
+        for bind in &self.binds {
+            if !bind.is_dir {
+                touch(&bind.target)?;
+            }
+            mount(
+                Some(&bind.source),
+                &bind.target,
+                None,
+                libc::MS_BIND | libc::MS_REC,
+            )?;
+            if bind.read_only {
+                mount(
+                    None,
+                    &bind.target,
+                    None,
+                    libc::MS_BIND
+                        | libc::MS_REMOUNT
+                        | libc::MS_RDONLY
+                        | bind.locked_flags,
+                )?;
+            }
+        }
+        // a fresh procfs can be refused (e.g. inside containers with masked
+        // /proc entries), fall back to the host's
+        let proc_flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+        if mount(Some(c"proc"), &self.proc, Some(c"proc"), proc_flags).is_err()
+        {
+            mount(
+                Some(c"/proc"),
+                &self.proc,
+                None,
+                libc::MS_BIND | libc::MS_REC,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn mount(
+    source: Option<&CStr>,
+    target: &CStr,
+    fstype: Option<&CStr>,
+    flags: libc::c_ulong,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::mount(
+            source.map_or(std::ptr::null(), CStr::as_ptr),
+            target.as_ptr(),
+            fstype.map_or(std::ptr::null(), CStr::as_ptr),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    check(ret).map(|_| ())
+}
+
+fn mkdir(path: &CStr) -> io::Result<()> {
+    if unsafe { libc::mkdir(path.as_ptr(), 0o755) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EEXIST) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn touch(path: &CStr) -> io::Result<()> {
+    let fd = check(unsafe {
+        libc::open(
+            path.as_ptr(),
+            libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC,
+            0o644,
+        )
+    })?;
+    unsafe { libc::close(fd) };
+    Ok(())
+}
+
+fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+    let fd = check(unsafe {
+        libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC)
+    })?;
+    let written =
+        unsafe { libc::write(fd, contents.as_ptr().cast(), contents.len()) };
+    unsafe { libc::close(fd) };
+    if written == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Close every file descriptor from `first` on
+fn close_fds_from(first: libc::c_uint) {
+    let closed = unsafe {
+        libc::syscall(libc::SYS_close_range, first, libc::c_uint::MAX, 0)
+    };
+    if closed == 0 {
+        return;
+    }
+    // close_range needs Linux 5.9
+    let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+    for fd in first as libc::c_long..max {
+        unsafe { libc::close(fd as libc::c_int) };
+    }
+}
+
+/// Wait for the sandboxed job and translate its status into an exit code
+fn wait_for(pid: libc::pid_t) -> libc::c_int {
+    let mut status = 0;
+    while unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            return 1;
+        }
+    }
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else {
+        128 + libc::WTERMSIG(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    /// Unprivileged user namespaces may be disabled, the sandbox can't work
+    /// without them
+    fn user_namespaces() -> bool {
+        let mut cmd = Command::new("true");
+        // SAFETY: unshare is a raw syscall
+        unsafe {
+            cmd.pre_exec(|| {
+                check(libc::unshare(libc::CLONE_NEWUSER)).map(|_| ())
+            });
+        }
+        cmd.status().is_ok_and(|s| s.success())
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "piper-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("couldn't create test directory");
+        dir
+    }
+
+    /// `script` run by sh in a sandbox for a store entry in `dir`
+    fn sandboxed(dir: &Path, script: &str, inputs: Vec<PathBuf>) -> Command {
+        let run = dir.join("entry").join("run");
+        fs::create_dir_all(&run).expect("couldn't create run directory");
+        let sandbox =
+            Sandbox::new(dir.join("root"), dir.join("entry"), inputs, false);
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script).current_dir(&run);
+        sandbox
+            .apply(&mut cmd, &run)
+            .expect("couldn't prepare sandbox");
+        cmd
+    }
+
+    #[test]
+    fn spawn_returns_while_the_job_runs() {
+        if !user_namespaces() {
+            eprintln!("skipped, user namespaces are not available");
+            return;
+        }
+        let dir = test_dir("sandbox-spawn");
+        let started = Instant::now();
+        let mut child = sandboxed(&dir, "sleep 2", vec![])
+            .spawn()
+            .expect("couldn't start sandboxed job");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(child.wait().expect("couldn't wait for job").success());
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_inputs_are_visible() {
+        if !user_namespaces() {
+            eprintln!("skipped, user namespaces are not available");
+            return;
+        }
+        let dir = test_dir("sandbox-inputs");
+        let input = dir.join("input");
+        let secret = dir.join("secret");
+        for path in [&input, &secret] {
+            fs::create_dir_all(path).expect("couldn't create directory");
+            fs::write(path.join("file"), "contents")
+                .expect("couldn't write file");
+        }
+        let script = format!(
+            "cat {input}/file && ! touch {input}/new && ! cat {secret}/file",
+            input = input.display(),
+            secret = secret.display()
+        );
+        let status = sandboxed(&dir, &script, vec![input])
+            .status()
+            .expect("couldn't run sandboxed job");
+        assert!(status.success());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn linked_originals_are_read_only() {
+        if !user_namespaces() {
+            eprintln!("skipped, user namespaces are not available");
+            return;
+        }
+        let dir = test_dir("sandbox-links");
+        let input = dir.join("input");
+        let original = dir.join("original");
+        fs::create_dir_all(&input).expect("couldn't create input");
+        fs::write(&original, "contents").expect("couldn't write original");
+        std::os::unix::fs::symlink(&original, input.join("large"))
+            .expect("couldn't link original");
+        let script = format!(
+            "cat {input}/large && ! echo changed > {input}/large",
+            input = input.display()
+        );
+        let status = sandboxed(&dir, &script, vec![input])
+            .status()
+            .expect("couldn't run sandboxed job");
+        assert!(status.success());
+        assert_eq!(
+            fs::read_to_string(&original).expect("couldn't read original"),
+            "contents"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn root_is_removed_after_the_job() {
+        if !user_namespaces() {
+            eprintln!("skipped, user namespaces are not available");
+            return;
+        }
+        let dir = test_dir("sandbox-root");
+        let root = dir.join("root");
+        let status = sandboxed(&dir, "touch /tmp/file", vec![])
+            .status()
+            .expect("couldn't run sandboxed job");
+        assert!(status.success());
+        let sandbox =
+            Sandbox::new(root.clone(), dir.join("entry"), vec![], false);
+        sandbox.remove_root().expect("couldn't remove sandbox root");
+        assert!(!root.exists());
+        // removing it again, e.g. after a failed setup, is fine
+        sandbox.remove_root().expect("couldn't remove sandbox root");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
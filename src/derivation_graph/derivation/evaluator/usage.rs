@@ -0,0 +1,67 @@
+//! Resource usage of jobs run by the local runtime.
+//!
+//! Usage is taken from `wait4`, which includes every descendant the job waited
+//! for. When the job ran in its own cgroup (see [`super::limits`]) the peak
+//! memory and I/O of the cgroup are used instead, as they also cover
+//! processes that were never waited for.
+use super::limits::Cgroup;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// `ru_inblock` and `ru_oublock` count 512 byte blocks
+static BLOCK_SIZE: u64 = 512;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub wall_seconds: f64,
+    pub user_cpu_seconds: f64,
+    pub system_cpu_seconds: f64,
+    /// kilobytes
+    pub peak_rss: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+impl ResourceUsage {
+    pub fn new(
+        rusage: &libc::rusage,
+        wall: Duration,
+        cgroup: Option<&Cgroup>,
+    ) -> Self {
+        let mut usage = ResourceUsage {
+            wall_seconds: wall.as_secs_f64(),
+            user_cpu_seconds: seconds(rusage.ru_utime),
+            system_cpu_seconds: seconds(rusage.ru_stime),
+            peak_rss: rusage.ru_maxrss as u64,
+            read_bytes: rusage.ru_inblock as u64 * BLOCK_SIZE,
+            write_bytes: rusage.ru_oublock as u64 * BLOCK_SIZE,
+        };
+        if let Some(cgroup) = cgroup {
+            if let Some(peak) = cgroup.peak_memory() {
+                usage.peak_rss = usage.peak_rss.max(peak / 1024);
+            }
+            if let Some((read, write)) = cgroup.io_bytes() {
+                usage.read_bytes = read;
+                usage.write_bytes = write;
+            }
+        }
+        usage
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "wall {:.1}s, user {:.1}s, system {:.1}s, peak {} MB",
+            self.wall_seconds,
+            self.user_cpu_seconds,
+            self.system_cpu_seconds,
+            self.peak_rss / 1024
+        )
+    }
+}
+
+fn seconds(time: libc::timeval) -> f64 {
+    time.tv_sec as f64 + time.tv_usec as f64 / 1_000_000.0
+}
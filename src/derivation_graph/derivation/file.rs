@@ -1,10 +1,18 @@
+use super::canonical::CanonicalHasher;
 use super::{Derivation, DerivationHash, DisplayTable, File};
+use crate::config::{BYTES_PER_MB, Config};
+use crate::store::file_hashes::FileStamp;
+use crate::store::{DerivationMetadata, Status, Store};
 use comfy_table::modifiers::UTF8_ROUND_CORNERS;
 use comfy_table::presets::UTF8_FULL;
 use comfy_table::{ContentArrangement, Table};
-use sha2::Digest;
-use std::io::{BufRead, Read};
+use pattern::Pattern;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use std::{fs, io};
 use steel::SteelErr;
 use steel::rvals::{FromSteelVal, IntoSteelVal};
@@ -12,25 +20,179 @@ use steel::steel_vm::builtin::BuiltInModule;
 use steel::steel_vm::register_fn::RegisterFn;
 use steel_derive::Steel;
 
-#[derive(Steel, Clone)]
+pub mod pattern;
+
+#[derive(Steel, Clone, Copy)]
 pub enum HashMethod {
     Contents,
     Timestamp,
+    /// contents, or the timestamp for files larger than `cacheFilesizeCutoff`
+    Auto,
+}
+
+/// How files are hashed, taken from the config
+struct Hashing {
+    method: HashMethod,
+    /// cache of contents hashes
+    store: Store,
+    /// size in bytes above which `HashMethod::Auto` uses the timestamp
+    cutoff: Option<u64>,
+}
+
+impl Hashing {
+    fn new(method: HashMethod, config: &Config) -> Self {
+        Hashing {
+            method,
+            store: Store::new(config.work_dir()),
+            cutoff: config
+                .get_usize("cacheFilesizeCutoff")
+                .map(|mb| mb as u64 * BYTES_PER_MB),
+        }
+    }
+
+    fn file(&self, path: PathBuf) -> io::Result<File> {
+        let hash = calculate_hash(&path, self)?;
+        Ok(File {
+            hash,
+            path,
+            files: None,
+        })
+    }
+
+    /// Add the contents or the timestamp of `path` to `hasher`
+    fn add_file(
+        &self,
+        hasher: &mut CanonicalHasher,
+        path: &Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<()> {
+        let method = match self.method {
+            HashMethod::Auto
+                if self.cutoff.is_some_and(|c| metadata.len() > c) =>
+            {
+                HashMethod::Timestamp
+            }
+            HashMethod::Auto => HashMethod::Contents,
+            method => method,
+        };
+        match method {
+            HashMethod::Contents | HashMethod::Auto => {
+                let contents = contents_hash(path, metadata, &self.store)?;
+                hasher.field("contents").field(contents);
+            }
+            HashMethod::Timestamp => {
+                let modified = metadata.modified()?.duration_since(UNIX_EPOCH);
+                let modified = modified.unwrap_or_default();
+                hasher.field("timestamp").field(format!(
+                    "{}.{:09}",
+                    modified.as_secs(),
+                    modified.subsec_nanos()
+                ));
+            }
+        };
+        Ok(())
+    }
 }
 
 impl File {
     pub fn new(
         path: String,
         hash_method: HashMethod,
+        config: Config,
+    ) -> Result<File, SteelErr> {
+        Ok(Hashing::new(hash_method, &config).file(PathBuf::from(path))?)
+    }
+
+    /// Hash many files at once, spread over all cpus
+    pub fn new_many(
+        paths: Vec<String>,
+        hash_method: HashMethod,
+        config: Config,
+    ) -> Result<Vec<File>, SteelErr> {
+        let hashing = Hashing::new(hash_method, &config);
+        Ok(parallel(&paths, |p| hashing.file(PathBuf::from(p)))?)
+    }
+
+    /// A directory, hashed by the files below it. Only files matching one of
+    /// the `include` patterns (all files if there are none) and none of the
+    /// `exclude` patterns are part of it, see [`pattern`].
+    pub fn new_directory(
+        path: String,
+        include: Vec<String>,
+        exclude: Vec<String>,
+        hash_method: HashMethod,
+        config: Config,
     ) -> Result<File, SteelErr> {
+        let hashing = Hashing::new(hash_method, &config);
         let path = PathBuf::from(path);
-        let hash = calculate_hash(&path, hash_method)?;
-        Ok(File { hash, path })
+        let patterns = |globs: Vec<String>| {
+            globs
+                .iter()
+                .map(|g| Pattern::new(g))
+                .collect::<Result<Vec<Pattern>, String>>()
+                .map_err(|e| {
+                    SteelErr::new(steel::rerrs::ErrorKind::BadSyntax, e)
+                })
+        };
+        let include = patterns(include)?;
+        let exclude = patterns(exclude)?;
+
+        let mut files = Vec::new();
+        pattern::walk(
+            &path,
+            Path::new(""),
+            None,
+            &mut |relative, metadata| {
+                if exclude.iter().any(|p| p.matches(relative)) {
+                    return false;
+                }
+                if metadata.is_file()
+                    && (include.is_empty()
+                        || include.iter().any(|p| p.matches(relative)))
+                {
+                    files.push(relative.to_path_buf());
+                }
+                true
+            },
+        )?;
+        let hash = calculate_directory_hash(&path, &files, &hashing)?;
+        Ok(File {
+            hash,
+            path,
+            files: Some(files),
+        })
     }
+
+    /// Paths of the files matching `glob`, see [`pattern`]
+    pub fn glob(glob: String) -> Result<Vec<String>, String> {
+        Ok(pattern::expand(&glob)?
+            .into_iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect())
+    }
+
     pub fn as_derivation(&self) -> Derivation {
         Derivation::File(self.clone())
     }
 
+    /// Description of the file for `derivation.json` in the store
+    pub fn metadata(&self, status: Status) -> DerivationMetadata {
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "path".to_string(),
+            serde_json::Value::from(self.path.to_string_lossy()),
+        );
+        DerivationMetadata {
+            name: path_name(&self.path).to_string(),
+            aliases: vec![],
+            hash: self.hash.to_string(),
+            attributes,
+            inputs: vec![],
+            script: String::new(),
+            status,
+        }
+    }
+
     pub fn display(&self) -> DisplayTable {
         let mut table = Table::new();
         table
@@ -47,33 +209,97 @@ impl File {
 
 fn calculate_hash(
     path: &Path,
-    hash_method: HashMethod,
-) -> std::io::Result<DerivationHash> {
-    let mut hasher = sha2::Sha256::new();
-    match hash_method {
-        HashMethod::Contents => {
-            // iterates through file, updating the hasher as it goes, allowing very
-            // large files to be hashed if necessary, only the hash is stored in memory
-            let file = fs::File::open(path)?;
-            for l in io::BufReader::new(file).lines().map_while(Result::ok) {
-                hasher.update(l)
-            }
-        }
-        HashMethod::Timestamp => {
-            hasher.update(format!("{:?}", std::fs::metadata(path)?.modified()?))
-        }
-    };
+    hashing: &Hashing,
+) -> io::Result<DerivationHash> {
+    let metadata = fs::metadata(path)?;
+    let mut hasher = CanonicalHasher::new("file");
+    hashing.add_file(&mut hasher, path, &metadata)?;
+    hasher.field(path.as_os_str().as_bytes());
+
+    let hash = format!("{}-{}", hasher.finish(), path_name(path));
+    Ok(DerivationHash(hash))
+}
 
-    let path_name: &str = match path.file_name() {
+/// Hash of the files of a directory, in sorted order, with their paths
+/// relative to it and whether they are executable
+fn calculate_directory_hash(
+    path: &Path,
+    files: &[PathBuf],
+    hashing: &Hashing,
+) -> io::Result<DerivationHash> {
+    let mut hasher = CanonicalHasher::new("directory");
+    hasher.field(path.as_os_str().as_bytes());
+    let digests = parallel(files, |file| {
+        let path = path.join(file);
+        let metadata = fs::metadata(&path)?;
+        let mut hasher = CanonicalHasher::new("directory-file");
+        hashing.add_file(&mut hasher, &path, &metadata)?;
+        let executable = metadata.permissions().mode() & 0o111 != 0;
+        Ok((executable, hasher.finish()))
+    })?;
+    for (file, (executable, digest)) in files.iter().zip(digests) {
+        hasher
+            .field(file.as_os_str().as_bytes())
+            .field(if executable { "x" } else { "-" })
+            .field(digest);
+    }
+
+    let hash = format!("{}-{}", hasher.finish(), path_name(path));
+    Ok(DerivationHash(hash))
+}
+
+fn path_name(path: &Path) -> &str {
+    match path.file_name() {
         Some(v) => v.to_str().unwrap_or("None"),
         None => "None",
-    };
+    }
+}
 
-    hasher.update(format!("{:?}", path));
+/// Apply `f` to every item, spread over all cpus, keeping the order
+fn parallel<T: Sync, R: Send>(
+    items: &[T],
+    f: impl Fn(&T) -> io::Result<R> + Sync,
+) -> io::Result<Vec<R>> {
+    let threads = std::thread::available_parallelism().map_or(1, usize::from);
+    let chunk_size = items.len().div_ceil(threads).max(1);
+    let results = std::thread::scope(|scope| {
+        let f = &f;
+        let workers: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter().map(f).collect::<io::Result<Vec<R>>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().expect("file hashing thread panicked"))
+            .collect::<io::Result<Vec<Vec<R>>>>()
+    })?;
+    Ok(results.into_iter().flatten().collect())
+}
 
-    let result = hasher.finalize();
-    let hash = format!("{:x}-{}", result, path_name);
-    Ok(DerivationHash(hash))
+/// SHA-256 of the raw bytes of `path`, taken from the store's cache if the
+/// file didn't change since it was last read
+fn contents_hash(
+    path: &Path,
+    metadata: &fs::Metadata,
+    store: &Store,
+) -> io::Result<String> {
+    let stamp = FileStamp::of(metadata);
+    let key = fs::canonicalize(path)?;
+    if let Some(sha256) = store.cached_file_hash(&key, &stamp) {
+        return Ok(sha256);
+    }
+    // the file is streamed through the hasher, allowing very large files
+    // to be hashed, only the hash is stored in memory
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    let sha256 = format!("{:x}", hasher.finalize());
+    // the cache only saves time, a file it can't be written to still works
+    let _ = store.record_file_hash(&key, &stamp, &sha256);
+    Ok(sha256)
 }
 
 pub fn register_steel_functions(
@@ -88,7 +314,12 @@ pub fn register_steel_functions(
         "File::HashTimestamp",
         HashMethod::Timestamp {}.into_steelval()?,
     );
+    module
+        .register_value("File::HashAuto", HashMethod::Auto {}.into_steelval()?);
     module.register_fn("File::new", File::new);
+    module.register_fn("File::new_many", File::new_many);
+    module.register_fn("File::new_directory", File::new_directory);
+    module.register_fn("File::glob", File::glob);
     module.register_fn("File::as_derivation", File::as_derivation);
     Ok(())
 }
@@ -0,0 +1,188 @@
+//! Glob patterns for `glob!` and the include and exclude lists of
+//! `directory!`.
+//!
+//! `*` matches any part of a file name, `?` a single character, `[abc]` one
+//! of the listed characters (`[!abc]` any other) and `**` any number of
+//! directories, e.g. `data/**/*.fastq.gz`. Patterns are translated to
+//! anchored regular expressions and matched against `/` separated paths.
+use regex::Regex;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn new(glob: &str) -> Result<Pattern, String> {
+        let mut regex = String::from("^");
+        let mut chars = glob.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    // "**/" also matches no directory at all
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                '[' => {
+                    let mut class = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            closed = true;
+                            break;
+                        }
+                        class.push(c);
+                    }
+                    if !closed {
+                        return Err(format!("unclosed [ in pattern {}", glob));
+                    }
+                    let class = match class.strip_prefix('!') {
+                        Some(rest) => format!("^{}", rest),
+                        None => class,
+                    };
+                    regex.push_str(&format!(
+                        "[{}]",
+                        class.replace('\\', "\\\\")
+                    ));
+                }
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Regex::new(&regex)
+            .map(|regex| Pattern { regex })
+            .map_err(|e| format!("invalid pattern {}: {}", glob, e))
+    }
+
+    /// true if `path`, relative to where the pattern applies, matches
+    pub fn matches(&self, path: &Path) -> bool {
+        self.regex.is_match(&path.to_string_lossy())
+    }
+}
+
+fn is_glob(component: &str) -> bool {
+    component.contains(['*', '?', '['])
+}
+
+/// Paths of the files matching `glob`, sorted. Only the directories below
+/// the part of the pattern without wildcards are searched.
+pub fn expand(glob: &str) -> Result<Vec<PathBuf>, String> {
+    let components: Vec<&str> = glob.split('/').collect();
+    let literal = components.iter().take_while(|c| !is_glob(c)).count();
+    if literal == components.len() {
+        // no wildcards, the pattern is just a path
+        let path = PathBuf::from(glob);
+        return Ok(if path.is_file() { vec![path] } else { vec![] });
+    }
+    let base = match components[..literal].join("/") {
+        b if b.is_empty() && glob.starts_with('/') => PathBuf::from("/"),
+        b if b.is_empty() => PathBuf::from("."),
+        b => PathBuf::from(b),
+    };
+    let rest = components[literal..].join("/");
+    let pattern = Pattern::new(&rest)?;
+    // without "**" files can only be as deep as the pattern
+    let depth = (!rest.contains("**")).then_some(components.len() - literal);
+
+    let mut found = Vec::new();
+    walk(&base, Path::new(""), depth, &mut |relative, metadata| {
+        if metadata.is_file() && pattern.matches(relative) {
+            found.push(if literal == 0 && !glob.starts_with('/') {
+                relative.to_path_buf()
+            } else {
+                base.join(relative)
+            });
+        }
+        true
+    })
+    .map_err(|e| format!("couldn't list {}: {}", base.display(), e))?;
+    found.sort();
+    Ok(found)
+}
+
+/// Call `visit` with the path relative to `root` and the metadata of every
+/// entry below `root/relative`, in sorted order. Directories `visit` returns
+/// false for aren't descended into, neither are symlinks to directories.
+pub fn walk(
+    root: &Path,
+    relative: &Path,
+    depth: Option<usize>,
+    visit: &mut impl FnMut(&Path, &fs::Metadata) -> bool,
+) -> io::Result<()> {
+    if depth == Some(0) {
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(root.join(relative))?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for name in entries {
+        let relative = relative.join(name);
+        let path = root.join(&relative);
+        let Ok(metadata) = fs::metadata(&path) else {
+            // dangling symlink
+            continue;
+        };
+        let is_link = fs::symlink_metadata(&path)?.is_symlink();
+        if visit(&relative, &metadata) && metadata.is_dir() && !is_link {
+            walk(root, &relative, depth.map(|d| d - 1), visit)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        Pattern::new(glob)
+            .expect("invalid pattern")
+            .matches(Path::new(path))
+    }
+
+    #[test]
+    fn wildcards_stay_in_a_directory() {
+        assert!(matches("*.fastq.gz", "sample.fastq.gz"));
+        assert!(!matches("*.fastq.gz", "run1/sample.fastq.gz"));
+        assert!(matches("sample?.txt", "sample1.txt"));
+        assert!(!matches("sample?.txt", "sample10.txt"));
+        assert!(!matches("?", "/"));
+    }
+
+    #[test]
+    fn double_star_matches_any_depth() {
+        assert!(matches("data/**/*.txt", "data/a.txt"));
+        assert!(matches("data/**/*.txt", "data/x/y/a.txt"));
+        assert!(!matches("data/**/*.txt", "other/a.txt"));
+        assert!(matches("data/**", "data/x/y"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("sample[12].txt", "sample1.txt"));
+        assert!(!matches("sample[12].txt", "sample3.txt"));
+        assert!(matches("sample[!12].txt", "sample3.txt"));
+        assert!(!matches("sample[!12].txt", "sample1.txt"));
+        assert!(matches("[a-c]", "b"));
+        assert!(Pattern::new("sample[12.txt").is_err());
+    }
+
+    #[test]
+    fn other_characters_are_literal() {
+        assert!(!matches("a.txt", "abtxt"));
+        assert!(matches("a+(b).txt", "a+(b).txt"));
+        assert!(!matches("a.txt", "a.txt.bak"));
+        assert!(!matches("a.txt", "x/a.txt"));
+    }
+}
@@ -1,8 +1,8 @@
-use std::collections::HashMap;
-use sha2::Digest;
+use std::collections::{BTreeMap, HashMap};
 use steel::steel_vm::builtin::BuiltInModule;
 use steel::steel_vm::register_fn::RegisterFn;
 
+use super::canonical::CanonicalHasher;
 use super::{Derivation, DisplayTable, Output, DerivationHash};
 use comfy_table::modifiers::UTF8_ROUND_CORNERS;
 use comfy_table::presets::UTF8_FULL;
@@ -10,6 +10,9 @@ use comfy_table::{ContentArrangement, Table};
 
 impl Output {
     pub fn new(outputs: HashMap<String, Derivation>) -> Output {
+        // sorted by name so the hash and edges don't depend on HashMap order
+        let outputs: BTreeMap<String, Derivation> =
+            outputs.into_iter().collect();
         let hash = calculate_hash(&outputs);
         Output{
             hash,
@@ -34,12 +37,12 @@ impl Output {
     }
 }
 
-fn calculate_hash(outputs: &HashMap<String, Derivation>) -> DerivationHash {
-    let mut hasher = sha2::Sha256::new();
+fn calculate_hash(outputs: &BTreeMap<String, Derivation>) -> DerivationHash {
+    let mut hasher = CanonicalHasher::new("output");
     for (k,v) in outputs.iter(){
-        hasher.update(format!("{}{}", k, v.hash()));
+        hasher.field(k).field(v.hash().to_string());
     }
-    let hash = format!("{:x}-Output", hasher.finalize());
+    let hash = format!("{}-Output", hasher.finish());
     DerivationHash(hash)
 }
 
@@ -1,13 +1,15 @@
+use super::canonical::CanonicalHasher;
 use super::evaluator;
 use scriptstring::ScriptString;
 /// implementation for Process derivation
 use super::{Derivation, DerivationHash, Process};
 use crate::config::{Config, ParamValue};
+use crate::store::{DerivationMetadata, Status, Store};
 use comfy_table::modifiers::UTF8_ROUND_CORNERS;
 use comfy_table::presets::UTF8_FULL;
 use comfy_table::{ContentArrangement, Table};
-use sha2::Digest;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
 use steel::SteelErr;
 use steel::{
     SteelVal,
@@ -38,20 +40,63 @@ fn use_default_if_exists(
     steel_defaults
 }
 
+/// Attributes piper itself reads. Besides `shell` and `container`, which are
+/// part of `hash_inputs`, they don't change what a script writes to `${out}`,
+/// so they are left out of the hash unless a pipeline lists them in
+/// `hash-attributes`.
+static PIPER_ATTRIBUTES: [&str; 18] = [
+    "name",
+    "script",
+    "shell",
+    "container",
+    "time",
+    "memory",
+    "cpus",
+    "workDir",
+    "sandbox",
+    "network",
+    "contentAddressed",
+    "hash-attributes",
+    "hash-env",
+    "hash-extra",
+    "hash-interpreter",
+    "outputHash",
+    "volatile",
+    "ttl",
+];
+
 fn calculate_hash(
-    name: &String,
-    script: &String,
-    container: &Option<String>,
-    shell: &String,
+    script: &str,
+    hash_inputs: &BTreeMap<String, String>,
 ) -> DerivationHash {
-    let container_string = container.clone().unwrap_or("".to_string());
-
-    let mut hasher = sha2::Sha256::new();
-    let combined = format!("{}{}{}{}", name, script, container_string, shell);
-    hasher.update(combined);
-    let result = hasher.finalize();
-    let hash = format!("{:x}-{}", result, name); // {:x} works because of the LowerHex trait
-    DerivationHash(hash)
+    let mut hasher = CanonicalHasher::new("process");
+    hasher.field(script);
+    for (key, value) in hash_inputs {
+        hasher.field(key).field(value);
+    }
+    DerivationHash(hasher.finish())
+}
+
+/// Hash of a fixed-output process, which only depends on the declared hash
+/// of its output (e.g. the checksum of a download), like fixed-output
+/// derivations in Nix. Piper checks `sha256:` hashes once the process
+/// finished, for any other kind the script has to make sure the output
+/// matches.
+fn fixed_output_hash(output_hash: &str) -> DerivationHash {
+    let mut hasher = CanonicalHasher::new("fixed-output");
+    hasher.field(output_hash);
+    DerivationHash(hasher.finish())
+}
+
+/// `outputHash` with a `sha256:` checksum or `git:` commit id in lowercase,
+/// so the case it was written in doesn't change the hash
+fn normalise_output_hash(output_hash: String) -> String {
+    match output_hash.split_once(':') {
+        Some((kind @ ("sha256" | "git"), value)) => {
+            format!("{}:{}", kind, value.to_lowercase())
+        }
+        _ => output_hash,
+    }
 }
 
 macro_rules! extract_attribute {
@@ -65,6 +110,111 @@ macro_rules! extract_attribute {
     }};
 }
 
+/// Everything besides the script the output of a process may depend on:
+///
+/// - `shell`, `container` and the version of the interpreter, unless
+///   `hash-interpreter` is `#f`
+/// - the attributes listed in `hash-attributes`, or every attribute that
+///   piper doesn't know about if there is no such list
+/// - the values of the environment variables listed in `hash-env`
+/// - `hash-extra`, for external dependencies like a database snapshot id
+fn hash_inputs(
+    attributes: &HashMap<String, SteelVal>,
+    merged_attributes: &HashMap<String, SteelVal>,
+    config_keys: &HashSet<String>,
+    container: &Option<String>,
+    shell: &str,
+) -> Result<BTreeMap<String, String>, SteelErr> {
+    let mut inputs = BTreeMap::new();
+    inputs.insert("shell".to_string(), shell.to_string());
+    // hosts sharing a store may have different versions of the same
+    // interpreter, `hash-interpreter : #f` lets them share results anyway
+    let interpreter =
+        extract_attribute!(merged_attributes, "hash-interpreter", bool)
+            .unwrap_or(true);
+    match container {
+        Some(container) => {
+            inputs.insert("container".to_string(), container.clone());
+        }
+        // inside a container the image decides the interpreter version
+        None if interpreter => {
+            inputs
+                .insert("interpreter".to_string(), interpreter_version(shell));
+        }
+        None => {}
+    }
+
+    let listed =
+        extract_attribute!(merged_attributes, "hash-attributes", Vec<String>);
+    let hashed: Vec<String> = match listed {
+        Some(names) => names,
+        None => attributes
+            .keys()
+            .filter(|k| {
+                !PIPER_ATTRIBUTES.contains(&k.as_str())
+                    && !config_keys.contains(*k)
+            })
+            .cloned()
+            .collect(),
+    };
+    for name in hashed {
+        if let Some(value) = merged_attributes.get(&name) {
+            inputs.insert(
+                format!("attribute:{}", name),
+                to_json(value).to_string(),
+            );
+        }
+    }
+
+    let env = extract_attribute!(merged_attributes, "hash-env", Vec<String>);
+    for name in env.unwrap_or_default() {
+        if let Ok(value) = std::env::var(&name) {
+            inputs.insert(format!("env:{}", name), value);
+        }
+    }
+
+    if let Some(extra) = merged_attributes.get("hash-extra") {
+        inputs.insert("extra".to_string(), to_json(extra).to_string());
+    }
+    Ok(inputs)
+}
+
+/// First line `<shell> --version` prints, empty if it can't be run. Only run
+/// once per shell.
+fn interpreter_version(shell: &str) -> String {
+    static VERSIONS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    let mut versions = VERSIONS
+        .get_or_init(Default::default)
+        .lock()
+        .expect("interpreter versions lock poisoned");
+    versions
+        .entry(shell.to_string())
+        .or_insert_with(|| {
+            // through sh, shells like "/usr/bin/env bash" have arguments
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} --version", shell))
+                .stdin(std::process::Stdio::null())
+                .output();
+            let Ok(output) = output else {
+                return String::new();
+            };
+            if !output.status.success() {
+                return String::new();
+            }
+            // some interpreters, e.g. python 2, print their version to stderr
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            stdout
+                .lines()
+                .chain(stderr.lines())
+                .find(|l| !l.trim().is_empty())
+                .unwrap_or_default()
+                .to_string()
+        })
+        .clone()
+}
+
 #[derive(Steel)]
 enum AttributeError {
     Required(String),
@@ -111,6 +261,8 @@ impl Process {
 
 
         // attributes from the config
+        let config_keys: HashSet<String> =
+            config.config.keys().cloned().collect();
         let merged_attributes =
             use_default_if_exists(config.config, attributes.clone());
 
@@ -118,6 +270,8 @@ impl Process {
 
         let memory = extract_attribute!(merged_attributes, "memory", usize);
 
+        let cpus = extract_attribute!(merged_attributes, "cpus", usize);
+
         let shell = extract_attribute!(merged_attributes, "shell", String)
             .ok_or_else(|| {
                 AttributeError::Required("shell".to_string()).into_steel()
@@ -128,23 +282,61 @@ impl Process {
                 AttributeError::Required("workDir".to_string()).into_steel()
             })?;
 
+        let sandbox = extract_attribute!(merged_attributes, "sandbox", bool)
+            .unwrap_or(false);
+
+        let network = extract_attribute!(merged_attributes, "network", bool)
+            .unwrap_or(false);
+
+        let content_addressed =
+            extract_attribute!(merged_attributes, "contentAddressed", bool)
+                .unwrap_or(false);
+
+        let output_hash =
+            extract_attribute!(merged_attributes, "outputHash", String)
+                .map(normalise_output_hash);
+
+        let volatile = extract_attribute!(merged_attributes, "volatile", bool)
+            .unwrap_or(false);
+
+        let ttl = extract_attribute!(merged_attributes, "ttl", usize);
+
         let container = None; // TODO need to add container handling
 
-        let hash = calculate_hash(&name, &script.to_string(), &container, &shell);
+        let hash_inputs = hash_inputs(
+            &attributes,
+            &merged_attributes,
+            &config_keys,
+            &container,
+            &shell,
+        )?;
+        let hash = match &output_hash {
+            Some(output_hash) => fixed_output_hash(output_hash),
+            None => calculate_hash(&script.to_string(), &hash_inputs),
+        };
 
         let d = Process {
             attributes: merged_attributes.clone(),
             hash,
             script: script.clone(),
             name,
+            aliases: Vec::new(),
             inward_edges: get_inward_edges(script),
             container,
             time,
             memory,
+            cpus,
             shell,
             hpc_runtime: None,
             container_runtime: None,
             work_dir,
+            sandbox,
+            network,
+            content_addressed,
+            hash_inputs,
+            output_hash,
+            volatile,
+            ttl,
         };
 
         Ok(d)
@@ -177,11 +369,56 @@ impl Process {
                 self.container.clone().unwrap_or("None".to_string()),
             ])
             .add_row(vec!["shell".to_string(), self.shell.clone()])
+            .add_row(vec!["sandbox".to_string(), self.sandbox.to_string()])
             .add_row(vec!["script".to_string(), self.script()]);
 
         DisplayTable { table }
     }
 
+    /// The hash of the process with the hashes of its inputs replaced by
+    /// the checksums of their outputs, for inputs that are in `store`. See
+    /// [`crate::store::realisations`].
+    pub fn resolved_hash(&self, store: &Store) -> DerivationHash {
+        if self.output_hash.is_some() {
+            // doesn't depend on the inputs at all
+            return self.hash.clone();
+        }
+        let mut script = self.script.to_string();
+        for input in &self.inward_edges {
+            if let Some(completion) = store.entry(input).completion() {
+                script = script.replace(
+                    &input.to_string(),
+                    &format!("content-{}", completion.out_checksum),
+                );
+            }
+        }
+        calculate_hash(&script, &self.hash_inputs)
+    }
+
+    /// true if `name` is the name of this process or of one with the same
+    /// hash
+    pub fn has_name(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|a| a == name)
+    }
+
+    /// Description of the process for `derivation.json` in the store
+    pub fn metadata(&self, status: Status) -> DerivationMetadata {
+        DerivationMetadata {
+            name: self.name.clone(),
+            aliases: self.aliases.clone(),
+            hash: self.hash.to_string(),
+            attributes: self
+                .attributes
+                .iter()
+                .filter(|(k, _)| k.as_str() != "script")
+                .map(|(k, v)| (k.clone(), to_json(v)))
+                .collect(),
+            inputs: self.inward_edges.iter().map(|i| i.to_string()).collect(),
+            script: self.script(),
+            status,
+        }
+    }
+
     // TODO need to rewrite this to have its own method
     pub fn run(&self) -> Option<evaluator::HPCRuntime> {
             evaluator::run_derivation(self)
@@ -219,6 +456,36 @@ impl std::fmt::Debug for Process {
     }
 }
 
+/// Convert an attribute to json, derivations are represented by their hash
+fn to_json(val: &SteelVal) -> serde_json::Value {
+    match val {
+        SteelVal::BoolV(v) => serde_json::Value::from(*v),
+        SteelVal::IntV(v) => serde_json::Value::from(*v),
+        SteelVal::NumV(v) => serde_json::Value::from(*v),
+        SteelVal::StringV(v) => serde_json::Value::from(v.to_string()),
+        _ => {
+            if let Ok(derivation) = Derivation::from_steelval(val) {
+                return serde_json::Value::from(derivation.hash().to_string());
+            }
+            if let Ok(list) = Vec::<SteelVal>::from_steelval(val) {
+                return list.iter().map(to_json).collect();
+            }
+            if let Ok(map) = HashMap::<SteelVal, SteelVal>::from_steelval(val) {
+                return serde_json::Value::Object(
+                    map.iter()
+                        .map(|(k, v)| {
+                            let key = String::from_steelval(k)
+                                .unwrap_or_else(|_| k.to_string());
+                            (key, to_json(v))
+                        })
+                        .collect(),
+                );
+            }
+            serde_json::Value::from(val.to_string())
+        }
+    }
+}
+
 fn extract_derivation_hashes(val: SteelVal) -> Vec<DerivationHash> {
     let mut vec = Vec::<DerivationHash>::new();
     extract_derivation_hashes_recursive(val, &mut vec);
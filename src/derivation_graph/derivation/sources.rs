@@ -0,0 +1,133 @@
+//! Scripts of the fixed-output processes behind `fetch!`, `git!` and
+//! `unpack!`.
+//!
+//! Remote inputs are ordinary processes whose hash comes from their
+//! `outputHash` attribute instead of their script, so they are cached under
+//! what they produce rather than where it came from. The scripts check the
+//! output themselves and fail if it doesn't match.
+use super::Derivation;
+use std::fs;
+use steel::steel_vm::builtin::BuiltInModule;
+use steel::steel_vm::register_fn::RegisterFn;
+
+static URL_SCHEMES: [&str; 3] = ["http://", "https://", "file://"];
+
+/// Script downloading `url` to `${out}`, failing unless its SHA-256 is
+/// `sha256`
+pub fn fetch_script(url: String, sha256: String) -> Result<String, String> {
+    if !URL_SCHEMES.iter().any(|s| url.starts_with(s)) {
+        return Err(format!(
+            "can't fetch {}, only {} urls are supported",
+            url,
+            URL_SCHEMES.join(", ")
+        ));
+    }
+    let sha256 = sha256.to_lowercase();
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("{} is not a SHA-256 checksum", sha256));
+    }
+    Ok(format!(
+        r#"set -eu
+curl --fail --silent --show-error --location --retry 3 --output download {url}
+actual=$(sha256sum download | cut -d ' ' -f 1)
+if [ "$actual" != "{sha256}" ]; then
+    echo "checksum mismatch for {url}: expected {sha256}, got $actual" >&2
+    exit 1
+fi
+mv download ${{out}}
+"#,
+        url = quote(&url),
+        sha256 = sha256,
+    ))
+}
+
+/// Script checking out `commit` of the repository at `url` to `${out}`,
+/// with its submodules if `submodules` is true. `.git` directories are
+/// removed so the output only depends on the commit.
+pub fn git_script(
+    url: String,
+    commit: String,
+    submodules: bool,
+) -> Result<String, String> {
+    let commit = commit.to_lowercase();
+    // sha1 or sha256 repositories, abbreviated ids could become ambiguous
+    if ![40, 64].contains(&commit.len())
+        || !commit.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(format!("{} is not a full commit id", commit));
+    }
+    // scripts run in the store, so local repositories need absolute paths
+    let url = if url.contains("://") || (url.contains('@') && url.contains(':'))
+    {
+        url
+    } else {
+        fs::canonicalize(&url)
+            .map_err(|e| format!("couldn't find repository {}: {}", url, e))?
+            .to_string_lossy()
+            .into_owned()
+    };
+    let submodules = if submodules {
+        "git -c protocol.file.allow=always submodule --quiet update --init \
+         --recursive\n"
+    } else {
+        ""
+    };
+    Ok(format!(
+        r#"set -eu
+git init --quiet ${{out}}
+cd ${{out}}
+git remote add origin {url}
+git fetch --quiet --depth 1 origin {commit} || git fetch --quiet origin
+git -c advice.detachedHead=false checkout --quiet {commit}
+{submodules}actual=$(git rev-parse HEAD)
+if [ "$actual" != "{commit}" ]; then
+    echo "checked out $actual instead of {commit} from {url}" >&2
+    exit 1
+fi
+find . -name .git -prune -exec rm -rf {{}} +
+"#,
+        url = quote(&url),
+        commit = commit,
+        submodules = submodules,
+    ))
+}
+
+/// Script extracting the `.tar.gz`, `.tar.zst` or `.zip` archive bound to
+/// `archive` to `${out}`. The format is told from the first bytes rather than
+/// the name, owners are dropped and permissions normalised, so extraction
+/// only depends on the archive.
+pub fn unpack_script() -> String {
+    String::from(
+        r#"set -eu
+archive=${archive}
+mkdir ${out}
+case $(od -A n -N 4 -t x1 "$archive" | tr -d ' \n') in
+    1f8b*) gzip -dc "$archive" | tar -x --no-same-owner -C ${out} -f - ;;
+    28b52ffd) zstd -dcq "$archive" | tar -x --no-same-owner -C ${out} -f - ;;
+    504b0304) unzip -q "$archive" -d ${out} ;;
+    *)
+        echo "$archive is not a .tar.gz, .tar.zst or .zip archive" >&2
+        exit 1
+        ;;
+esac
+chmod -R u+rwX,go-w ${out}
+"#,
+    )
+}
+
+/// `outputHash` of unpacking `archive`, which only depends on its hash
+pub fn unpack_output_hash(archive: Derivation) -> String {
+    format!("unpack:{}", archive.hash())
+}
+
+/// `value` as a single quoted shell word
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r#"'\''"#))
+}
+
+pub fn register_steel_functions(module: &mut BuiltInModule) {
+    module.register_fn("fetch-script", fetch_script);
+    module.register_fn("git-script", git_script);
+    module.register_fn("unpack-script", unpack_script);
+    module.register_fn("unpack-output-hash", unpack_output_hash);
+}
@@ -1,91 +1,294 @@
-use crate::derivation_graph::derivation::evaluator::HPCRuntimeFunctions;
-use crate::derivation_graph::{
-    DerivationGraph, derivation::Derivation, derivation::DerivationHash,
-    derivation::Process,
-};
-use std::collections::HashSet;
-use std::collections::VecDeque;
+use crate::config::BYTES_PER_MB;
+use crate::derivation_graph::DerivationGraph;
+use crate::derivation_graph::derivation::canonical::HASH_SCHEME;
+use crate::derivation_graph::derivation::{Derivation, DerivationHash};
+use crate::store::Store;
+use crate::store::optimise::Savings;
+use std::collections::{HashMap, HashSet};
+
+pub mod cancel;
+pub mod scheduler;
+
+/// Reasons running (part of) the graph stopped
+#[derive(Debug)]
+pub enum RunError {
+    NotInGraph(DerivationHash),
+    NoOutputs,
+    /// the store couldn't be read or changed
+    Store(std::io::Error),
+    /// store entries whose `out` doesn't match their manifest
+    Corrupted(Vec<DerivationHash>),
+    /// rebuilds that differ from the cached result, with the differences
+    NotDeterministic(Vec<String>),
+    /// jobs that failed, with the reason they failed
+    Failed(Vec<String>),
+    /// piper received `signal` and stopped the jobs in `stopped`
+    Interrupted {
+        signal: i32,
+        stopped: Vec<DerivationHash>,
+    },
+}
+
+impl RunError {
+    /// Exit code piper should use, following the shell's convention for
+    /// processes stopped by a signal
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::Interrupted { signal, .. } => 128 + signal,
+            _ => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::NotInGraph(v) => {
+                write!(f, "Derivation {} not in process graph", v)
+            }
+            RunError::NoOutputs => write!(f, "No outputs node!"),
+            RunError::Store(v) => write!(f, "Couldn't update the store: {}", v),
+            RunError::Corrupted(v) => {
+                write!(f, "{} corrupted store entries", v.len())?;
+                for hash in v {
+                    write!(f, "\n  {}", hash)?;
+                }
+                Ok(())
+            }
+            RunError::NotDeterministic(v) => write!(
+                f,
+                "{} derivation(s) aren't deterministic\n{}",
+                v.len(),
+                v.join("\n")
+            ),
+            RunError::Failed(v) => write!(f, "{}", v.join("\n")),
+            RunError::Interrupted { signal, stopped } => {
+                write!(
+                    f,
+                    "Interrupted by signal {}, stopped {} running derivation(s)",
+                    signal,
+                    stopped.len()
+                )?;
+                for hash in stopped {
+                    write!(f, "\n  {}", hash)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
 impl DerivationGraph {
     /// runs arbitrary derivation based on its hash
     pub fn run_derivation(
         &self,
         derivation_hash: DerivationHash,
-    ) -> Result<(), String> {
-        let mut run_order = VecDeque::<Vec<Derivation>>::new();
-        let mut stop = false;
-        let root = self
-            .nodes
-            .get(&derivation_hash)
-            .ok_or("Derivation not in process graph".to_string());
-        run_order.push_back(vec![root?.clone()]);
-        while !stop {
-            let mut iteration: Vec<Derivation> = Vec::new();
-            let last_iter = run_order.back().expect(
-                "no first element of run_order, this should never happen",
-            );
-            for i in last_iter {
-                if let Some(edges) = i.clone().inputs() {
-                    let mut derivations: Vec<Derivation> =
-                        edges.iter().map(|edges| {
-                            self.nodes.get(edges).expect("inward edges are not in process graph, this should never happen").clone()
-                        }).collect();
-                    iteration.append(&mut derivations);
-                }
-            }
+    ) -> Result<(), RunError> {
+        self.run_graph(derivation_hash, false)
+    }
 
-            if iteration.is_empty() {
-                stop = true
-            } else {
-                run_order.push_back(iteration);
+    /// Every derivation depending on one of `roots`, directly or not,
+    /// including the roots themselves
+    pub fn dependents(
+        &self,
+        roots: impl IntoIterator<Item = DerivationHash>,
+    ) -> HashSet<DerivationHash> {
+        let mut dependents =
+            HashMap::<DerivationHash, Vec<DerivationHash>>::new();
+        for (hash, derivation) in &self.nodes {
+            for input in derivation.inputs().unwrap_or_default() {
+                dependents.entry(input).or_default().push(hash.clone());
             }
         }
-
-        let mut i = 0;
-        for iteration in run_order {
-            let iteration: Vec<&Process> = iteration
-                .iter()
-                .filter_map(|x| match x {
-                    Derivation::Process(v) => Some(v),
-                    _ => None,
-                })
-                .collect();
-            if iteration.is_empty(){
+        let mut found = HashSet::new();
+        let mut stack: Vec<DerivationHash> = roots.into_iter().collect();
+        while let Some(hash) = stack.pop() {
+            if found.contains(&hash) {
                 continue;
             }
-            i += 1;
-            println!(
-                "run iteration: {}, {:?}",
-                i,
-                iteration
-                    .iter()
-                    .map(|v| v.hash.clone())
-                    .collect::<Vec<DerivationHash>>()
-            );
-            let handles: Vec<
-                Option<
-                    crate::derivation_graph::derivation::evaluator::HPCRuntime,
-                >,
-            > = iteration
-                .iter()
-                .map(|derivation| derivation.run())
-                .collect();
+            if let Some(next) = dependents.get(&hash) {
+                stack.extend(next.iter().cloned());
+            }
+            found.insert(hash);
+        }
+        found
+    }
 
-            for mut handle in &mut handles.into_iter().flatten() {
-                handle.wait();
+    /// runs outputs derivation, rebuilding everything that is cached to
+    /// check that the results are the same
+    pub fn check(&self) -> Result<(), RunError> {
+        self.run_graph(
+            self.outputs.clone().ok_or(RunError::NoOutputs)?.hash(),
+            true,
+        )
+    }
+
+    fn run_graph(
+        &self,
+        derivation_hash: DerivationHash,
+        check: bool,
+    ) -> Result<(), RunError> {
+        // builds of a piper process that crashed are never reused
+        let store = Store::new(self.config.work_dir());
+        match store.recover() {
+            Ok(removed) => {
+                for path in removed {
+                    println!("removed unfinished build: {}", path.display());
+                }
+            }
+            Err(e) => {
+                println!("couldn't clean up {}: {}", store.root().display(), e)
+            }
+        }
+        match store.record_hash_scheme(HASH_SCHEME) {
+            Ok(Some(old)) => println!(
+                "hash scheme changed from version {} to {}, existing entries won't be reused",
+                old, HASH_SCHEME
+            ),
+            Ok(None) => {}
+            Err(e) => println!("couldn't record the hash scheme: {}", e),
+        }
+        let budget = scheduler::Budget::from_config(&self.config);
+        let result = scheduler::LocalScheduler::new(self, budget)
+            .with_check(check)
+            .run(derivation_hash.clone());
+        if self.config.get_bool("autoOptimise") {
+            match self.optimise(&store, derivation_hash.clone()) {
+                Ok(savings) if savings.linked_files > 0 => println!(
+                    "optimised: linked {} files, saved {} MB",
+                    savings.linked_files,
+                    savings.bytes / BYTES_PER_MB
+                ),
+                Ok(_) => {}
+                Err(e) => println!("couldn't optimise the store: {}", e),
+            }
+        }
+        if let Some(max) = self.config.get_usize("cacheMaxSize") {
+            if let Err(e) =
+                self.enforce_cache_size(&store, derivation_hash, max)
+            {
+                println!("couldn't shrink the store to cacheMaxSize: {}", e);
             }
         }
+        result
+    }
+
+    /// Deduplicate the outputs of `root` and everything it depends on, see
+    /// [`crate::store::optimise`]
+    pub fn optimise(
+        &self,
+        store: &Store,
+        root: DerivationHash,
+    ) -> Result<Savings, RunError> {
+        let mut total = Savings::default();
+        for hash in self.closure(root)?.keys() {
+            let savings = store
+                .optimise(&store.entry(hash))
+                .map_err(RunError::Store)?;
+            total.linked_files += savings.linked_files;
+            total.bytes += savings.bytes;
+        }
+        Ok(total)
+    }
 
+    /// Evict entries the pipeline doesn't need, least recently used first,
+    /// until the store is at most `max` megabytes
+    fn enforce_cache_size(
+        &self,
+        store: &Store,
+        root: DerivationHash,
+        max: usize,
+    ) -> Result<(), RunError> {
+        let mut roots: Vec<DerivationHash> =
+            self.closure(root)?.into_keys().collect();
+        if let Some(outputs) = &self.outputs {
+            roots.extend(self.closure(outputs.hash())?.into_keys());
+        }
+        let live = store.live(roots).map_err(RunError::Store)?;
+        let max_bytes = max as u64 * BYTES_PER_MB;
+        let (evicted, size) =
+            store.evict(&live, max_bytes).map_err(RunError::Store)?;
+        for hash in &evicted {
+            println!("evicted: {}", hash);
+        }
+        if size > max_bytes {
+            println!(
+                "the store is {} MB, over its cacheMaxSize of {} MB, but \
+                 everything left in it is in use",
+                size / BYTES_PER_MB,
+                max
+            );
+        }
         Ok(())
     }
 
+    /// Every derivation reachable from `root`, including itself
+    pub fn closure(
+        &self,
+        root: DerivationHash,
+    ) -> Result<HashMap<DerivationHash, &Derivation>, RunError> {
+        let mut nodes = HashMap::new();
+        let mut stack = vec![root];
+        while let Some(hash) = stack.pop() {
+            if nodes.contains_key(&hash) {
+                continue;
+            }
+            let derivation = self
+                .get(&hash)
+                .ok_or_else(|| RunError::NotInGraph(hash.clone()))?;
+            stack.extend(derivation.inputs().unwrap_or_default());
+            nodes.insert(hash, derivation);
+        }
+        Ok(nodes)
+    }
+
     /// runs outputs derivation
-    pub fn run(&self) -> Result<(), String> {
+    pub fn run(&self) -> Result<(), RunError> {
         self.run_derivation(
-            self.outputs.clone().ok_or_else(|| "No outputs node!")?.hash(),
-        );
-        // need to replace with custom error type for derivations
+            self.outputs.clone().ok_or(RunError::NoOutputs)?.hash(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derivation_graph::extract_graph;
+    use crate::vm::engine;
 
-        todo!()
+    #[test]
+    fn dependents_follow_inputs() {
+        let mut e = engine(None);
+        e.run(
+            r#"(define a (process! name : "a" script : "echo a > ${out}"))
+               (define b (process! name : "b" script : "cat ${a} > ${out}"))
+               (define c (process! name : "c" script : "cat ${b} > ${out}"))
+               (define d (process! name : "d" script : "cat ${a} > ${out}"))
+               (define e (process! name : "e" script : "echo e > ${out}"))"#
+                .to_string(),
+        )
+        .expect("invalid pipeline");
+        let graph = extract_graph(&mut e).expect("no graph");
+        let hash = |name: &str| {
+            graph
+                .nodes
+                .values()
+                .find_map(|d| match d {
+                    Derivation::Process(p) if p.name == name => Some(d.hash()),
+                    _ => None,
+                })
+                .expect("no such process")
+        };
+        let names = |names: &[&str]| -> HashSet<DerivationHash> {
+            names.iter().map(|n| hash(n)).collect()
+        };
+
+        assert_eq!(graph.dependents([hash("a")]), names(&["a", "b", "c", "d"]));
+        assert_eq!(graph.dependents([hash("b")]), names(&["b", "c"]));
+        assert_eq!(
+            graph.dependents([hash("c"), hash("e")]),
+            names(&["c", "e"])
+        );
+        assert!(graph.dependents([]).is_empty());
     }
 }
@@ -0,0 +1,64 @@
+//! SIGINT and SIGTERM handling while derivations run.
+//!
+//! Jobs run in their own process groups, so a Ctrl-C in the terminal only
+//! reaches piper. The first signal asks the scheduler to forward it to every
+//! running job and stop, a second one exits immediately.
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::{SigId, flag, low_level};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub struct Cancellation {
+    requested: Arc<AtomicBool>,
+    signal: Arc<AtomicUsize>,
+    handlers: Vec<SigId>,
+}
+
+impl Cancellation {
+    /// Install the handlers, they are removed again when this is dropped
+    pub fn install() -> std::io::Result<Self> {
+        let mut cancellation = Cancellation {
+            requested: Arc::new(AtomicBool::new(false)),
+            signal: Arc::new(AtomicUsize::new(0)),
+            handlers: vec![],
+        };
+        for signal in [SIGINT, SIGTERM] {
+            // checked before the flag below is set, so it only fires on the
+            // second signal
+            cancellation
+                .handlers
+                .push(flag::register_conditional_shutdown(
+                    signal,
+                    1,
+                    Arc::clone(&cancellation.requested),
+                )?);
+            cancellation.handlers.push(flag::register(
+                signal,
+                Arc::clone(&cancellation.requested),
+            )?);
+            cancellation.handlers.push(flag::register_usize(
+                signal,
+                Arc::clone(&cancellation.signal),
+                signal as usize,
+            )?);
+        }
+        Ok(cancellation)
+    }
+
+    /// The signal that was received, if any
+    pub fn requested(&self) -> Option<i32> {
+        if self.requested.load(Ordering::SeqCst) {
+            Some(self.signal.load(Ordering::SeqCst) as i32)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        for handler in self.handlers.drain(..) {
+            low_level::unregister(handler);
+        }
+    }
+}
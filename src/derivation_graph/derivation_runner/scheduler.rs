@@ -0,0 +1,831 @@
+//! Scheduler for derivations run by the local runtime.
+//!
+//! Derivations are started as soon as their inputs are done and their `cpus`
+//! and `memory` fit in what is left of the budget (`localCpus` and
+//! `localMemory` in the config, detected from the machine otherwise). Ready
+//! derivations with the longest chain of processes depending on them are
+//! admitted first, ties are broken by hash. Smaller ones fill up whatever is
+//! left, but not what the first derivation that didn't fit needs, so a large
+//! job isn't starved by a steady stream of small ones.
+//!
+//! With `piper run --check`, derivations that are already in the store are
+//! rebuilt in `.check/` as well, and their outputs compared with the cached
+//! ones to find steps that aren't deterministic.
+//!
+//! `volatile` processes, and processes whose result is older than their
+//! `ttl`, are rerun even if they are in the store, replacing the cached
+//! entry. Their dependents only rerun if the output actually changed.
+//!
+//! Derivations that have to be built are locked in the store first. If
+//! another piper process holds the lock, the derivation waits and is reused
+//! once that build finished.
+use super::RunError;
+use super::cancel::Cancellation;
+use crate::config::{BYTES_PER_MB, Config};
+use crate::derivation_graph::DerivationGraph;
+use crate::derivation_graph::derivation::evaluator::{
+    self, HPCRuntime, HPCRuntimeFunctions, JobError,
+};
+use crate::derivation_graph::derivation::{
+    Derivation, DerivationHash, File, Process,
+};
+use crate::store::locks::{BuildLock, LOCK_POLL};
+use crate::store::manifest::{Manifest, Node};
+use crate::store::{
+    Completion, Status, Store, StoreEntry, now, remove_tree, write_json,
+};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often running jobs are checked for completion
+static POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long interrupted jobs get to exit before they are killed
+static GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Resources available to, or claimed from, the local machine
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    pub cpus: usize,
+    /// megabytes
+    pub memory: usize,
+}
+
+impl Budget {
+    pub fn from_config(config: &Config) -> Budget {
+        Budget {
+            cpus: config.get_usize("localCpus").unwrap_or_else(detect_cpus),
+            memory: config
+                .get_usize("localMemory")
+                .unwrap_or_else(detect_memory),
+        }
+    }
+
+    fn fits(&self, used: Budget, claim: Budget) -> bool {
+        let total = used.plus(claim);
+        total.cpus <= self.cpus && total.memory <= self.memory
+    }
+
+    fn plus(self, other: Budget) -> Budget {
+        Budget {
+            cpus: self.cpus.saturating_add(other.cpus),
+            memory: self.memory.saturating_add(other.memory),
+        }
+    }
+}
+
+fn detect_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Total memory of the machine in megabytes, unlimited if unknown
+fn detect_memory() -> usize {
+    std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|m| {
+            m.lines()
+                .find_map(|l| l.strip_prefix("MemTotal:"))
+                .and_then(|v| {
+                    v.trim().trim_end_matches("kB").trim().parse().ok()
+                })
+        })
+        .map_or(usize::MAX, |kb: usize| kb / 1024)
+}
+
+struct Job {
+    hash: DerivationHash,
+    store: Store,
+    /// where the job is being built, see `Store::build_entry`
+    build: StoreEntry,
+    /// rebuilding a cached entry, see `Store::check_entry`
+    check: bool,
+    /// see `Process::resolved_hash`, only for content addressed processes
+    resolved: Option<DerivationHash>,
+    /// out checksum of the cached entry a stale job replaces, see `stale`
+    previous: Option<String>,
+    /// see `Process::output_hash`, only for fixed-output processes
+    output_hash: Option<String>,
+    /// held until the job is moved into the store, None for check jobs
+    _lock: Option<BuildLock>,
+    claim: Budget,
+    handle: HPCRuntime,
+}
+
+impl Job {
+    /// Record how the job ended and move it into the store, returns its
+    /// entry
+    fn record(&self, status: Status) -> Result<StoreEntry, String> {
+        if let Some(usage) = self.handle.usage() {
+            write_json(&self.build.usage_path(), &usage)
+                .map_err(|e| format!("couldn't write usage.json: {}", e))?;
+        }
+        if status == Status::Finished {
+            let metadata = self
+                .build
+                .read_metadata()
+                .map_err(|e| format!("couldn't read derivation.json: {}", e))?;
+            // runtimes that can't tell only report whether the job succeeded
+            let exit_status = self
+                .handle
+                .exit_status()
+                .and_then(|s| s.code())
+                .unwrap_or_default();
+            self.build
+                .complete(
+                    exit_status,
+                    self.store.input_checksums(&metadata.inputs),
+                )
+                .map_err(|e| format!("couldn't write complete.json: {}", e))?;
+        } else {
+            self.build.set_status(status).map_err(|e| {
+                format!("couldn't update derivation.json: {}", e)
+            })?;
+        }
+        let entry = self
+            .store
+            .commit(&self.build, &self.hash)
+            .map_err(|e| format!("couldn't move it into the store: {}", e))?;
+        let _ = entry.touch();
+        if let (Status::Finished, Some(resolved)) = (status, &self.resolved) {
+            if let Err(e) = self.store.record_realisation(resolved, &self.hash)
+            {
+                println!(
+                    "couldn't record {} as {}: {}",
+                    self.hash, resolved, e
+                );
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Record the job as failed because of `reason`, returns the failure to
+    /// report
+    fn fail(&self, reason: impl std::fmt::Display) -> String {
+        match self.record(Status::Failed) {
+            Ok(entry) => format!(
+                "{}: {}, logs are in {}",
+                self.hash,
+                reason,
+                entry.logs_dir().display()
+            ),
+            Err(e) => format!("{}: {}, {}", self.hash, reason, e),
+        }
+    }
+
+    /// Compare the output of a fixed-output job with its `outputHash` if that
+    /// is a `sha256:` checksum, other kinds are checked by the script
+    fn check_output_hash(&self) -> Result<(), String> {
+        let Some(expected) = self
+            .output_hash
+            .as_deref()
+            .and_then(|h| h.strip_prefix("sha256:"))
+        else {
+            return Ok(());
+        };
+        let manifest = Manifest::of(&self.build.out(), false)
+            .map_err(|e| format!("couldn't hash the output: {}", e))?;
+        match manifest.nodes.get(Path::new("")) {
+            Some(Node::File { sha256, .. }) if sha256 == expected => Ok(()),
+            Some(Node::File { sha256, .. }) => Err(format!(
+                "output hash mismatch, expected sha256:{} but got sha256:{}",
+                expected, sha256
+            )),
+            _ => Err(String::from("sha256 outputHash needs a file as output")),
+        }
+    }
+
+    /// Compare the rebuild of a check job with the cached entry, returns a
+    /// description of the differences if there are any
+    fn compare(&self) -> Result<Option<String>, String> {
+        let mismatches = self
+            .store
+            .entry(&self.hash)
+            .compare(&self.build.out())
+            .map_err(|e| format!("{}: couldn't compare: {}", self.hash, e))?;
+        if mismatches.is_empty() {
+            let _ = remove_tree(&self.build.path);
+            return Ok(None);
+        }
+        let mut description = format!(
+            "{}: rebuilt output differs from the cached one, the rebuild is \
+             in {}",
+            self.hash,
+            self.build.path.display()
+        );
+        for mismatch in mismatches {
+            description.push_str(&format!("\n    {}", mismatch));
+        }
+        Ok(Some(description))
+    }
+}
+
+/// A `File` derivation being put into the store, see `LocalScheduler::add_file`
+struct Adding {
+    hash: DerivationHash,
+    path: PathBuf,
+    /// held until the entry is in the store
+    _lock: Option<BuildLock>,
+    handle: JoinHandle<std::io::Result<StoreEntry>>,
+}
+
+pub struct LocalScheduler<'a> {
+    graph: &'a DerivationGraph,
+    budget: Budget,
+    used: Budget,
+    check: bool,
+}
+
+impl<'a> LocalScheduler<'a> {
+    pub fn new(graph: &'a DerivationGraph, budget: Budget) -> Self {
+        Self {
+            graph,
+            budget,
+            used: Budget::default(),
+            check: false,
+        }
+    }
+
+    /// Rebuild cached derivations and compare them with the store
+    pub fn with_check(mut self, check: bool) -> Self {
+        self.check = check;
+        self
+    }
+
+    /// Run every derivation `root` depends on, returns the failed jobs
+    pub fn run(&mut self, root: DerivationHash) -> Result<(), RunError> {
+        let cancellation =
+            Cancellation::install().expect("couldn't install signal handlers");
+        let nodes = self.graph.closure(root)?;
+        // keeps `piper gc` in other processes from deleting what this run
+        // needs
+        let _roots = Store::new(self.graph.config.work_dir())
+            .register_run(nodes.keys())
+            .map_err(RunError::Store)?;
+        let priorities = priorities(&nodes);
+        let mut pending: HashSet<DerivationHash> =
+            nodes.keys().cloned().collect();
+        let mut done = HashSet::<DerivationHash>::new();
+        // locked by other piper processes, with when that was last checked
+        let mut waiting = HashMap::<DerivationHash, Instant>::new();
+        let mut running = Vec::<Job>::new();
+        let mut adding = Vec::<Adding>::new();
+        let mut failures = Vec::<String>::new();
+        let mut differences = Vec::<String>::new();
+
+        loop {
+            if let Some(signal) = cancellation.requested() {
+                return Err(stop(running, adding, signal));
+            }
+            let mut progressed = false;
+            let mut ready: Vec<&Derivation> = pending
+                .iter()
+                .map(|h| nodes[h])
+                .filter(|d| inputs(d).iter().all(|i| done.contains(i)))
+                .collect();
+            ready.sort_by_key(|d| {
+                (Reverse(priorities[&d.hash()]), d.hash().to_string())
+            });
+            // claimed for the first process that didn't fit
+            let mut reserved: Option<Budget> = None;
+
+            for derivation in ready {
+                if let Derivation::File(file) = derivation {
+                    let store = Store::new(self.graph.config.work_dir());
+                    let lock = if store.entry(&file.hash).is_finished() {
+                        None
+                    } else {
+                        match lock(&store, &file.hash, &mut waiting) {
+                            Ok(Some(lock)) => Some(lock),
+                            Ok(None) => continue,
+                            Err(e) => {
+                                pending.remove(&file.hash);
+                                progressed = true;
+                                failures.push(format!(
+                                    "{}: couldn't lock: {}",
+                                    file.hash, e
+                                ));
+                                continue;
+                            }
+                        }
+                    };
+                    pending.remove(&file.hash);
+                    progressed = true;
+                    adding.push(Adding {
+                        hash: file.hash.clone(),
+                        path: file.path.clone(),
+                        _lock: lock,
+                        handle: self.add_file(file),
+                    });
+                    continue;
+                }
+                let Derivation::Process(process) = derivation else {
+                    // the other derivations have no work to do
+                    pending.remove(&derivation.hash());
+                    done.insert(derivation.hash());
+                    progressed = true;
+                    continue;
+                };
+                let store = Store::new(&process.work_dir);
+                // an earlier build of an external resource is out of date
+                let external = process.volatile || process.ttl.is_some();
+                let resolved = (process.content_addressed && !external)
+                    .then(|| process.resolved_hash(&store));
+                if resolved.as_ref().is_some_and(|r| reuse(process, &store, r))
+                {
+                    pending.remove(&process.hash);
+                    done.insert(process.hash.clone());
+                    progressed = true;
+                    continue;
+                }
+                // cached results take no resources, they mustn't wait for
+                // or hold back the budget
+                let cached = store.entry(&process.hash).is_finished()
+                    && stale(process, &store).is_none();
+                if cached && !self.check {
+                    // only used to pick what is evicted first, see
+                    // `cacheMaxSize`
+                    let _ = store.entry(&process.hash).touch();
+                    pending.remove(&process.hash);
+                    done.insert(process.hash.clone());
+                    progressed = true;
+                    continue;
+                }
+                let claim = self.claim(process);
+                // a job larger than the budget still runs, but on its own
+                if !running.is_empty()
+                    && !self.budget.fits(
+                        self.used.plus(reserved.unwrap_or_default()),
+                        claim,
+                    )
+                {
+                    reserved.get_or_insert(claim);
+                    continue;
+                }
+                // only derivations that have to be built are locked
+                let lock = if !cached {
+                    match lock(&store, &process.hash, &mut waiting) {
+                        Ok(Some(lock)) => Some(lock),
+                        Ok(None) => continue,
+                        Err(e) => {
+                            pending.remove(&process.hash);
+                            progressed = true;
+                            failures.push(format!(
+                                "{}: couldn't lock: {}",
+                                process.hash, e
+                            ));
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+                waiting.remove(&process.hash);
+                pending.remove(&process.hash);
+                progressed = true;
+                // again, another piper process may have rebuilt it meanwhile
+                let stale = stale(process, &store);
+                let started = match &stale {
+                    Some((reason, _)) => {
+                        println!("rerunning: {} ({})", process.hash, reason);
+                        let build = store.build_entry(&process.hash);
+                        Some(evaluator::start(process, &store, &build))
+                    }
+                    None => process.run(),
+                };
+                let (handle, build, check) = match started {
+                    Some(handle) => {
+                        if stale.is_none() {
+                            println!("running: {}", process.hash);
+                        }
+                        (handle, store.build_entry(&process.hash), false)
+                    }
+                    None if self.check => {
+                        // dependents use the cached entry, they don't have
+                        // to wait for the check
+                        done.insert(process.hash.clone());
+                        println!("checking: {}", process.hash);
+                        let build = store.check_entry(&process.hash);
+                        (evaluator::start(process, &store, &build), build, true)
+                    }
+                    None => {
+                        done.insert(process.hash.clone());
+                        continue;
+                    }
+                };
+                self.used.cpus += claim.cpus;
+                self.used.memory += claim.memory;
+                running.push(Job {
+                    hash: process.hash.clone(),
+                    store,
+                    build,
+                    check,
+                    resolved,
+                    previous: stale.map(|(_, previous)| previous),
+                    output_hash: process.output_hash.clone(),
+                    _lock: lock,
+                    claim,
+                    handle,
+                });
+            }
+
+            let mut i = 0;
+            while i < adding.len() {
+                if !adding[i].handle.is_finished() {
+                    i += 1;
+                    continue;
+                }
+                let file = adding.swap_remove(i);
+                progressed = true;
+                match file.handle.join().expect("copying a file panicked") {
+                    Ok(_) => {
+                        done.insert(file.hash);
+                    }
+                    Err(e) => failures.push(format!(
+                        "{}: couldn't copy {} into the store: {}",
+                        file.hash,
+                        file.path.display(),
+                        e
+                    )),
+                }
+            }
+
+            let mut i = 0;
+            while i < running.len() {
+                if !running[i].handle.finished() {
+                    i += 1;
+                    continue;
+                }
+                let mut job = running.swap_remove(i);
+                progressed = true;
+                self.used.cpus -= job.claim.cpus;
+                self.used.memory -= job.claim.memory;
+                match job.handle.wait() {
+                    Some(Err(e)) if job.check => failures.push(format!(
+                        "{}: check {}, logs are in {}",
+                        job.hash,
+                        e,
+                        job.build.logs_dir().display()
+                    )),
+                    _ if job.check => match job.compare() {
+                        Ok(None) => println!("deterministic: {}", job.hash),
+                        Ok(Some(difference)) => differences.push(difference),
+                        Err(e) => failures.push(e),
+                    },
+                    Some(Err(e)) => failures.push(job.fail(e)),
+                    _ => {
+                        if let Err(e) = job.check_output_hash() {
+                            failures.push(job.fail(e));
+                            continue;
+                        }
+                        let entry = match job.record(Status::Finished) {
+                            Ok(entry) => entry,
+                            Err(e) => {
+                                failures.push(format!("{}: {}", job.hash, e));
+                                continue;
+                            }
+                        };
+                        if let Some(usage) = job.handle.usage() {
+                            println!("finished: {} ({})", job.hash, usage);
+                        }
+                        if let Some(previous) = &job.previous {
+                            if entry
+                                .completion()
+                                .is_some_and(|c| c.out_checksum == *previous)
+                            {
+                                println!("unchanged: {}", job.hash);
+                            }
+                        }
+                        done.insert(job.hash);
+                    }
+                }
+            }
+
+            if running.is_empty()
+                && adding.is_empty()
+                && !progressed
+                && waiting.is_empty()
+            {
+                break;
+            }
+            if !progressed {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        // never became ready because one of their inputs failed
+        let mut skipped: Vec<DerivationHash> = pending
+            .into_iter()
+            .filter(|h| matches!(nodes[h], Derivation::Process(_)))
+            .collect();
+        skipped.sort_by_key(|h| h.to_string());
+        for hash in skipped {
+            println!("skipped: {}", hash);
+            failures.push(format!("{}: skipped, an input failed", hash));
+        }
+
+        if !failures.is_empty() {
+            failures.extend(differences);
+            Err(RunError::Failed(failures))
+        } else if !differences.is_empty() {
+            Err(RunError::NotDeterministic(differences))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Put the file or directory of a `File` derivation into the store, so
+    /// processes can read it through their `run/<hash>` symlinks. Runs in
+    /// its own thread, copying large inputs mustn't hold up the scheduler.
+    fn add_file(&self, file: &File) -> JoinHandle<std::io::Result<StoreEntry>> {
+        let config = &self.graph.config;
+        let link_above = config
+            .get_usize("cacheFilesizeCutoff")
+            .map(|mb| mb as u64 * BYTES_PER_MB);
+        let store = Store::new(config.work_dir());
+        let metadata = file.metadata(Status::Running);
+        let (path, files) = (file.path.clone(), file.files.clone());
+        std::thread::spawn(move || {
+            store.add_file(&metadata, &path, files.as_deref(), link_above)
+        })
+    }
+
+    /// Resources a process asks for, capped to the budget
+    fn claim(&self, process: &Process) -> Budget {
+        Budget {
+            cpus: process.cpus.unwrap_or(1).clamp(1, self.budget.cpus.max(1)),
+            memory: process.memory.unwrap_or(0).min(self.budget.memory),
+        }
+    }
+}
+
+/// Take the lock of `hash`, None if another piper process holds it. Locks
+/// found held are noted in `waiting` and only tried again after
+/// [`LOCK_POLL`].
+fn lock(
+    store: &Store,
+    hash: &DerivationHash,
+    waiting: &mut HashMap<DerivationHash, Instant>,
+) -> std::io::Result<Option<BuildLock>> {
+    if waiting.get(hash).is_some_and(|t| t.elapsed() < LOCK_POLL) {
+        return Ok(None);
+    }
+    match store.try_lock(hash)? {
+        Ok(lock) => {
+            waiting.remove(hash);
+            Ok(Some(lock))
+        }
+        Err(holder) => {
+            if waiting.insert(hash.clone(), Instant::now()).is_none() {
+                match holder {
+                    Some(h) => println!("waiting: {} (locked by {})", hash, h),
+                    None => println!("waiting: {}", hash),
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Create the entry of a content addressed `process` from an earlier build
+/// with the same resolved hash, returns false if there is none
+fn reuse(process: &Process, store: &Store, resolved: &DerivationHash) -> bool {
+    if store.entry(&process.hash).is_finished() {
+        return false;
+    }
+    let Some(source) = store.realisation(resolved) else {
+        return false;
+    };
+    // being built by another piper process, or reused by it
+    let Ok(Ok(_lock)) = store.try_lock(&process.hash) else {
+        return false;
+    };
+    if store.entry(&process.hash).is_finished() {
+        return false;
+    }
+    match store.reuse(&process.metadata(Status::Finished), &source) {
+        Ok(_) => {
+            println!("reused: {} (same inputs as {})", process.hash, source);
+            true
+        }
+        Err(e) => {
+            println!("couldn't reuse {} as {}: {}", source, process.hash, e);
+            false
+        }
+    }
+}
+
+/// Why the cached entry of `process` has to be rebuilt, along with its out
+/// checksum, None if it isn't cached or is still up to date. Inputs are
+/// compared with the checksums recorded when it was built, so an input
+/// rerun by this or an earlier run with a different output is noticed.
+fn stale(process: &Process, store: &Store) -> Option<(&'static str, String)> {
+    let cached = store.entry(&process.hash);
+    if !cached.is_finished() {
+        return None;
+    }
+    let completion = cached.completion()?;
+    let reason = if process.volatile {
+        "volatile"
+    } else if process.ttl.is_some_and(|ttl| expired(&completion, ttl)) {
+        "older than its ttl"
+    } else if completion.inputs.iter().any(|(input, built_against)| {
+        store
+            .entry(&DerivationHash::from(input.as_str()))
+            .completion()
+            .is_some_and(|c| c.out_checksum != *built_against)
+    }) {
+        "an input changed"
+    } else {
+        return None;
+    };
+    Some((reason, completion.out_checksum))
+}
+
+/// true if `completion` is more than `ttl` minutes old
+fn expired(completion: &Completion, ttl: usize) -> bool {
+    now().saturating_sub(completion.completed_at) >= ttl as u64 * 60
+}
+
+/// Forward `signal` to every running job, killing the ones that are still
+/// running after the grace period, and wait for the files being added
+fn stop(mut running: Vec<Job>, adding: Vec<Adding>, signal: i32) -> RunError {
+    println!("stopping {} running derivation(s)", running.len());
+    for job in &mut running {
+        job.handle.cancel(signal);
+    }
+    let started = Instant::now();
+    while !running.iter_mut().all(|j| j.handle.finished()) {
+        if started.elapsed() > GRACE_PERIOD {
+            for job in &mut running {
+                if !job.handle.finished() {
+                    job.handle.cancel(libc::SIGKILL);
+                }
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    // every job is reaped now, this only reaches what they left behind in
+    // their cgroups
+    for job in &mut running {
+        job.handle.cancel(libc::SIGKILL);
+    }
+    let stopped = running
+        .into_iter()
+        .filter_map(|mut j| {
+            if j.check {
+                j.handle.wait();
+                let _ = remove_tree(&j.build.path);
+                return None;
+            }
+            let status = match j.handle.wait() {
+                Some(Ok(_)) => Status::Finished,
+                Some(Err(JobError::Cancelled(_))) => Status::Interrupted,
+                _ => Status::Failed,
+            };
+            if let Err(e) = j.record(status) {
+                println!("couldn't record {}: {}", j.hash, e);
+            }
+            (status == Status::Interrupted).then_some(j.hash)
+        })
+        .collect();
+    // copies can't be interrupted, their locks are only released once they
+    // are done
+    for file in adding {
+        match file.handle.join() {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => println!(
+                "couldn't copy {} into the store: {}",
+                file.path.display(),
+                e
+            ),
+            Err(_) => println!("copying {} panicked", file.path.display()),
+        }
+    }
+    RunError::Interrupted { signal, stopped }
+}
+
+fn inputs(derivation: &Derivation) -> Vec<DerivationHash> {
+    derivation.inputs().unwrap_or_default()
+}
+
+/// Length of the longest chain of processes that depend on each derivation,
+/// counting the derivation itself
+fn priorities(
+    nodes: &HashMap<DerivationHash, &Derivation>,
+) -> HashMap<DerivationHash, usize> {
+    let mut dependents =
+        HashMap::<&DerivationHash, Vec<&DerivationHash>>::new();
+    // inputs in the graph that haven't been ordered yet
+    let mut remaining = HashMap::<&DerivationHash, usize>::new();
+    for (hash, derivation) in nodes {
+        let mut count = 0;
+        for input in inputs(derivation) {
+            if let Some((input, _)) = nodes.get_key_value(&input) {
+                dependents.entry(input).or_default().push(hash);
+                count += 1;
+            }
+        }
+        remaining.insert(hash, count);
+    }
+
+    // every derivation after its inputs, iteratively since pipelines can
+    // be deeper than the stack
+    let mut order: Vec<&DerivationHash> = remaining
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(hash, _)| *hash)
+        .collect();
+    let mut i = 0;
+    while i < order.len() {
+        for dependent in dependents.get(order[i]).into_iter().flatten() {
+            let count = remaining
+                .get_mut(dependent)
+                .expect("dependents are in the graph");
+            *count -= 1;
+            if *count == 0 {
+                order.push(dependent);
+            }
+        }
+        i += 1;
+    }
+
+    let mut priorities = HashMap::new();
+    for hash in order.into_iter().rev() {
+        let own = usize::from(matches!(nodes[hash], Derivation::Process(_)));
+        let longest = dependents
+            .get(hash)
+            .into_iter()
+            .flatten()
+            .map(|d| priorities[*d])
+            .max()
+            .unwrap_or(0);
+        priorities.insert(hash.clone(), own + longest);
+    }
+    priorities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derivation_graph::extract_graph;
+    use crate::vm::engine;
+    use std::collections::BTreeMap;
+
+    fn completed_minutes_ago(minutes: u64) -> Completion {
+        Completion {
+            exit_status: 0,
+            out_checksum: String::new(),
+            completed_at: now() - minutes * 60,
+            reused: None,
+            inputs: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        assert!(!expired(&completed_minutes_ago(0), 60));
+        assert!(!expired(&completed_minutes_ago(59), 60));
+        assert!(expired(&completed_minutes_ago(60), 60));
+        assert!(expired(&completed_minutes_ago(120), 60));
+    }
+
+    #[test]
+    fn longest_chain_first() {
+        let mut e = engine(None);
+        e.run(
+            r#"(define a (process! name : "a" script : "echo a > ${out}"))
+               (define b (process! name : "b" script : "cat ${a} > ${out}"))
+               (define c (process! name : "c" script : "cat ${b} > ${out}"))
+               (define d (process! name : "d" script : "echo d > ${out}"))
+               (define e
+                 (process! name : "e" script : "cat ${a} ${c} > ${out}"))
+               (output! "e" : e "d" : d)"#
+                .to_string(),
+        )
+        .expect("invalid pipeline");
+        let graph = extract_graph(&mut e).expect("no graph");
+        let mut nodes: HashMap<DerivationHash, &Derivation> =
+            graph.nodes.iter().map(|(h, d)| (h.clone(), d)).collect();
+        let outputs = graph.outputs.as_ref().expect("no outputs");
+        nodes.insert(outputs.hash(), outputs);
+
+        let priorities = priorities(&nodes);
+        let of = |name: &str| {
+            let hash = graph
+                .nodes
+                .iter()
+                .find(|(_, d)| {
+                    matches!(d, Derivation::Process(p) if p.name == name)
+                })
+                .map(|(h, _)| h)
+                .expect("no such process");
+            priorities[hash]
+        };
+        assert_eq!(["a", "b", "c", "d", "e"].map(of), [4, 3, 2, 1, 1]);
+        // only processes count
+        assert_eq!(priorities[&outputs.hash()], 0);
+    }
+}
@@ -1,13 +1,15 @@
 #![warn(clippy::unwrap_used)]
 
 mod bindings;
+mod commands;
 mod config;
 mod debug_utils;
 mod derivation_graph;
+mod store;
 mod vm;
 
 use crate::debug_utils::Runner;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use steel_repl::colored::Colorize;
 use vm::engine;
 
@@ -24,6 +26,64 @@ struct Cli {
     /// --config is the path to the piper config, a scheme file
     #[arg(short, long, default_value = ".piperConfig.scm")]
     config: std::path::PathBuf,
+
+    /// runs the pipeline if no command is given
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the pipeline, the same as giving no command
+    Run {
+        /// also rebuild cached derivations and report the ones whose
+        /// output changed
+        #[arg(long)]
+        check: bool,
+    },
+    /// Rebuild a store entry whose output was modified after it finished
+    Repair {
+        /// hash of the derivation to repair
+        hash: String,
+    },
+    /// Check store entries against the manifest recorded when they finished
+    Verify {
+        /// hashes of the entries to check, every finished entry if empty
+        hashes: Vec<String>,
+        /// mark corrupted entries invalid, so they are rebuilt
+        #[arg(long)]
+        invalidate: bool,
+    },
+    /// Mark derivations and everything depending on them invalid, so the
+    /// next run rebuilds them
+    Invalidate {
+        /// hashes of derivations or names of processes
+        #[arg(required = true)]
+        targets: Vec<String>,
+        /// delete the store entries instead
+        #[arg(long)]
+        delete: bool,
+    },
+    /// Delete store entries that aren't needed by the pipeline or a pinned
+    /// result
+    Gc {
+        /// only list what would be deleted
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Replace identical files in the store with hard links to one copy
+    Optimise,
+    /// Keep results and everything they depend on through `piper gc`
+    Pin {
+        /// hashes of the derivations to keep
+        #[arg(required = true)]
+        hashes: Vec<String>,
+    },
+    /// Let `piper gc` delete pinned results again
+    Unpin {
+        #[arg(required = true)]
+        hashes: Vec<String>,
+    },
 }
 
 /// The entrypoint function for piper
@@ -46,6 +106,26 @@ fn main() {
             Ok(v) => v,
             Err(e) => {engine.raise_error(e); return}
         };
-        dag.run().unwrap_or_else(|e| println!("{}: {}", "Error".red().bold(), e));
+        let result = match args.command {
+            None | Some(Command::Run { check: false }) => dag.run(),
+            Some(Command::Run { check: true }) => dag.check(),
+            Some(Command::Repair { hash }) => {
+                commands::repair(&dag, &hash.as_str().into())
+            }
+            Some(Command::Verify { hashes, invalidate }) => {
+                commands::verify(&dag, &hashes, invalidate)
+            }
+            Some(Command::Invalidate { targets, delete }) => {
+                commands::invalidate(&dag, &targets, delete)
+            }
+            Some(Command::Gc { dry_run }) => commands::gc(&dag, dry_run),
+            Some(Command::Optimise) => commands::optimise(&dag),
+            Some(Command::Pin { hashes }) => commands::pin(&dag, &hashes),
+            Some(Command::Unpin { hashes }) => commands::unpin(&dag, &hashes),
+        };
+        if let Err(e) = result {
+            println!("{}: {}", "Error".red().bold(), e);
+            std::process::exit(e.exit_code());
+        }
     }
 }
@@ -3,6 +3,8 @@
 (config workDir ".work")
 (config entryPoint "src/main.scm")
 (config shell "/usr/bin/env bash")
+(config sandbox #f)
+(config cacheFilesizeCutoff 1024)
 
 ;; Params
 (param test (hash "key" "value"))
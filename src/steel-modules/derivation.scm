@@ -2,6 +2,12 @@
 (provide process
 	 process!
 	 file!
+	 files!
+	 directory!
+	 glob!
+	 fetch!
+	 git!
+	 unpack!
 	 output!
 	 count-nodes
 	 display-nodes
@@ -100,13 +106,69 @@
 		   (DG::Output::new
 		    (hash-helper rest ...))))]))
 
-(define (file! path #:hashMethod [hashMethod DG::File::HashTimestamp])
+(define (file! path #:hashMethod [hashMethod DG::File::HashAuto])
   (let* ((derivation
-	  (~> (DG::File::new path hashMethod)
+	  (~> (DG::File::new path hashMethod DG::config)
 	      (DG::File::as_derivation))))
     (DG::add_derivation DG::graph derivation)
   ))
 
+;; like file!, but hashes the files in parallel
+(define (files! paths #:hashMethod [hashMethod DG::File::HashAuto])
+  (map (lambda (file)
+	 (DG::add_derivation DG::graph (DG::File::as_derivation file)))
+       (DG::File::new_many paths hashMethod DG::config)))
+
+;; a whole directory, optionally only the files matching #:include and
+;; none of #:exclude, e.g. #:exclude '("**/*.log" "tmp")
+(define (directory! path
+		    #:include [include '()]
+		    #:exclude [exclude '()]
+		    #:hashMethod [hashMethod DG::File::HashAuto])
+  (let* ((derivation
+	  (~> (DG::File::new_directory path include exclude hashMethod DG::config)
+	      (DG::File::as_derivation))))
+    (DG::add_derivation DG::graph derivation)
+  ))
+
+;; the files matching a pattern like "data/*.fastq.gz", as a list of
+;; derivations or, with #:dataframe #t, a dataframe with a path column
+(define (glob! pattern
+	       #:hashMethod [hashMethod DG::File::HashAuto]
+	       #:dataframe [dataframe #f])
+  (let ((files (files! (DG::File::glob pattern) #:hashMethod hashMethod)))
+    (if dataframe
+	(DG::Dataframe::from_files files)
+	files)))
+
+;; downloads url (http, https or file) and checks it against its sha256,
+;; the hash only depends on the checksum so moving the file elsewhere
+;; doesn't rerun anything downstream
+(define (fetch! url sha256 #:name [name "fetch"])
+  (process (hash 'name name
+		 'network #t
+		 'outputHash (string-append "sha256:" sha256)
+		 'script (DG::fetch-script url sha256))))
+
+;; checks out a commit of a git repository (a url or a local path), the hash
+;; only depends on the commit id
+(define (git! url commit
+	      #:submodules [submodules #f]
+	      #:name [name "git"])
+  (process (hash 'name name
+		 'network #t
+		 'outputHash (string-append "git:" commit
+					    (if submodules "+submodules" ""))
+		 'script (DG::git-script url commit submodules))))
+
+;; the extracted tree of a .tar.gz, .tar.zst or .zip file!, fetch! or
+;; process output, hashed by the hash of the archive
+(define (unpack! archive #:name [name "unpack"])
+  (process (hash 'name name
+		 'outputHash (DG::unpack-output-hash archive)
+		 'script (DG::unpack-script))
+	   #:bindings `((archive ,archive))))
+
 (define (count-nodes)
   (DG::node_count DG::graph))
 
@@ -0,0 +1,512 @@
+//! Layout of the derivation store, the `workDir` from the config.
+//!
+//! Every derivation that has been run gets one directory named after its
+//! hash:
+//!
+//! ```text
+//! <workDir>/
+//!   <hash>/
+//!     derivation.json   name, attributes, inputs, script and status
+//!     run/              working directory of the script
+//!       .cmd            the script as it was run
+//!       <input hash>    symlink to <workDir>/<input hash>/out
+//!     out               what the script wrote to ${out}, read-only once finished
+//!     logs/
+//!       stdout
+//!       stderr
+//!     usage.json        resources used by the last run
+//!     manifest.json     SHA-256 of every file in out, once it succeeded
+//!     complete.json     checksums of out and the inputs, once it succeeded
+//!     accessed          when the entry was last built or reused
+//!     optimised         present once out was deduplicated by `piper optimise`
+//!   .build/<hash>.<host>.<pid>/  an entry while piper process <pid> on
+//!                       <host> builds it
+//!   .check/<hash>.<host>.<pid>/  a rebuild by `piper run --check`
+//!   .sandbox/<hash>/    mount point of the sandbox root while <hash> runs
+//!   .roots/<hash>       derivations pinned with `piper pin`
+//!   .runs/<host>.<pid>  hashes needed by the running piper process <pid>
+//!   .trash/             entries that are being deleted
+//!   .links/<sha256>     files shared by several entries through hard links
+//!   .realisations/<resolved hash>  entry built from these input contents
+//!   .hash-scheme        version of the serialization hashes are computed from
+//!   .file-hashes/       cached contents hashes of input files
+//!   .locks/<hash>       held by the piper process building <hash>
+//! ```
+//!
+//! Derivations are built in `.build` and renamed to `<hash>` once they
+//! stopped, so an entry is never seen half written. Only entries with a
+//! `complete.json` and the status `finished` are valid, anything else is
+//! rerun. Builds left in `.build` by a piper process on the same host that
+//! no longer exists are removed by [`Store::recover`]. Piper processes
+//! sharing a store take a lock before building an entry and wait for each
+//! other, see [`locks`].
+//! The `out` of a finished entry is made read-only, so scripts reading it
+//! through the symlinks in `run/` can't change it by accident. Entries that
+//! aren't needed by any root are deleted by `piper gc`, see [`gc`]. `File`
+//! derivations get an entry too, holding a copy of the file, see [`files`].
+//! Directories starting with a `.` belong to piper and are never
+//! derivations.
+use crate::derivation_graph::derivation::DerivationHash;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod file_hashes;
+pub mod files;
+pub mod gc;
+pub mod locks;
+pub mod manifest;
+pub mod optimise;
+pub mod realisations;
+
+use manifest::{Manifest, Mismatch};
+
+/// The derivation store rooted at `workDir`
+#[derive(Debug, Clone)]
+pub struct Store {
+    root: PathBuf,
+}
+
+impl Store {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn entry(&self, hash: &DerivationHash) -> StoreEntry {
+        StoreEntry {
+            path: self.root.join(hash.to_string()),
+        }
+    }
+
+    /// Where the sandbox root of `hash` is mounted while it runs
+    pub fn sandbox_dir(&self, hash: &DerivationHash) -> PathBuf {
+        self.root.join(".sandbox").join(hash.to_string())
+    }
+
+    fn builds_dir(&self) -> PathBuf {
+        self.root.join(".build")
+    }
+
+    /// Entry `hash` is built in by this piper process
+    pub fn build_entry(&self, hash: &DerivationHash) -> StoreEntry {
+        StoreEntry {
+            path: self.builds_dir().join(format!("{}.{}", hash, owner_tag())),
+        }
+    }
+
+    /// Where this piper process rebuilds `hash` to check that it is
+    /// deterministic, see `piper run --check`
+    pub fn check_entry(&self, hash: &DerivationHash) -> StoreEntry {
+        StoreEntry {
+            path: self.root.join(".check").join(format!(
+                "{}.{}",
+                hash,
+                owner_tag()
+            )),
+        }
+    }
+
+    /// Move a stopped build to its place in the store, replacing whatever
+    /// was there before. The rename is atomic, readers either see the old
+    /// entry, no entry or the complete new one.
+    pub fn commit(
+        &self,
+        build: &StoreEntry,
+        hash: &DerivationHash,
+    ) -> io::Result<StoreEntry> {
+        let entry = self.entry(hash);
+        if fs::symlink_metadata(&entry.path).is_ok() {
+            self.remove(&entry)?;
+        }
+        fs::rename(&build.path, &entry.path)?;
+        sync_dir(&self.root)?;
+        Ok(entry)
+    }
+
+    /// Delete an entry, it is moved out of the way first so it disappears
+    /// at once even if deleting it is interrupted
+    pub fn remove(&self, entry: &StoreEntry) -> io::Result<()> {
+        let Some(name) = entry.path.file_name() else {
+            return Ok(());
+        };
+        let trash = self.root.join(".trash");
+        fs::create_dir_all(&trash)?;
+        let target =
+            trash.join(format!("{}.{}", name.to_string_lossy(), owner_tag()));
+        fs::rename(&entry.path, &target)?;
+        remove_tree(&target)
+    }
+
+    /// Record that entries are hashed with scheme `version`, returns the
+    /// version recorded before if it was a different one. Entries hashed
+    /// with another scheme are never reused, their hashes can't match.
+    pub fn record_hash_scheme(&self, version: u32) -> io::Result<Option<u32>> {
+        let path = self.root.join(".hash-scheme");
+        let recorded = fs::read_to_string(&path)
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok());
+        if recorded == Some(version) {
+            return Ok(None);
+        }
+        fs::create_dir_all(&self.root)?;
+        fs::write(&path, format!("{}\n", version))?;
+        Ok(recorded)
+    }
+
+    /// `out_checksum` of every finished entry among `inputs`, recorded in
+    /// the completion of what is built from them
+    pub fn input_checksums<'a>(
+        &self,
+        inputs: impl IntoIterator<Item = &'a String>,
+    ) -> BTreeMap<String, String> {
+        inputs
+            .into_iter()
+            .filter_map(|input| {
+                let entry = self.entry(&DerivationHash::from(input.as_str()));
+                let completion = entry.completion()?;
+                Some((input.clone(), completion.out_checksum))
+            })
+            .collect()
+    }
+
+    /// Remove builds and deleted entries left behind by piper processes on
+    /// this host that no longer exist, e.g. after a crash. Those of other
+    /// hosts can't be told apart from running ones and are left alone.
+    /// Returns the removed paths.
+    pub fn recover(&self) -> io::Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        for dir in [
+            self.builds_dir(),
+            self.root.join(".check"),
+            self.root.join(".trash"),
+        ] {
+            let children = match fs::read_dir(&dir) {
+                Ok(c) => c,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for child in children {
+                let path = child?.path();
+                if !abandoned(&path) {
+                    continue;
+                }
+                remove_tree(&path)?;
+                removed.push(path);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// `<host>.<pid>` of this piper process, appended to the names of the
+/// directories it owns
+fn owner_tag() -> String {
+    format!("{}.{}", hostname(), std::process::id())
+}
+
+/// true if `<name>.<host>.<pid>` was left behind by a piper process on this
+/// host that no longer runs
+fn abandoned(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    // hashes don't contain dots, host names may
+    let Some((host, pid)) = name
+        .split_once('.')
+        .and_then(|(_, owner)| owner.rsplit_once('.'))
+    else {
+        return false;
+    };
+    host == hostname() && !process_alive(pid)
+}
+
+/// true if a process with the id `pid` exists on this machine
+fn process_alive(pid: &str) -> bool {
+    let Ok(pid) = pid.parse::<libc::pid_t>() else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    exists || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Name of this host, which tells apart piper processes on the nodes of a
+/// cluster sharing a store
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is valid for its length, the name is truncated to
+    // fit
+    let result =
+        unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) };
+    if result != 0 {
+        return String::from("localhost");
+    }
+    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..end]).into_owned()
+}
+
+/// Recursively remove write permission, symlinks are left alone
+pub fn make_read_only(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+    // children first, the directory has to stay writable until then
+    if metadata.is_dir() {
+        for child in fs::read_dir(path)? {
+            make_read_only(&child?.path())?;
+        }
+    }
+    let mode = metadata.permissions().mode() & !0o222;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// `fs::remove_dir_all` for trees that may contain read-only outputs
+pub fn remove_tree(path: &Path) -> io::Result<()> {
+    // only directories need to be writable to delete what is in them
+    fn open_dirs(path: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.is_dir() {
+            return Ok(());
+        }
+        let mode = metadata.permissions().mode();
+        if mode & 0o700 != 0o700 {
+            fs::set_permissions(
+                path,
+                fs::Permissions::from_mode(mode | 0o700),
+            )?;
+        }
+        for child in fs::read_dir(path)? {
+            open_dirs(&child?.path())?;
+        }
+        Ok(())
+    }
+    match open_dirs(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        v => v?,
+    }
+    fs::remove_dir_all(path)
+}
+
+/// Seconds since the unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn sync_dir(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// The directory of a single derivation in the store
+#[derive(Debug, Clone)]
+pub struct StoreEntry {
+    pub path: PathBuf,
+}
+
+impl StoreEntry {
+    pub fn run_dir(&self) -> PathBuf {
+        self.path.join("run")
+    }
+
+    pub fn out(&self) -> PathBuf {
+        self.path.join("out")
+    }
+
+    pub fn logs_dir(&self) -> PathBuf {
+        self.path.join("logs")
+    }
+
+    pub fn stdout(&self) -> PathBuf {
+        self.logs_dir().join("stdout")
+    }
+
+    pub fn stderr(&self) -> PathBuf {
+        self.logs_dir().join("stderr")
+    }
+
+    pub fn metadata_path(&self) -> PathBuf {
+        self.path.join("derivation.json")
+    }
+
+    pub fn usage_path(&self) -> PathBuf {
+        self.path.join("usage.json")
+    }
+
+    fn optimised_path(&self) -> PathBuf {
+        self.path.join("optimised")
+    }
+
+    fn accessed_path(&self) -> PathBuf {
+        self.path.join("accessed")
+    }
+
+    /// Record that the entry was just built or reused
+    pub fn touch(&self) -> io::Result<()> {
+        fs::write(self.accessed_path(), now().to_string())
+    }
+
+    /// When the entry was last built or reused, in seconds since the unix
+    /// epoch
+    pub fn last_access(&self) -> u64 {
+        fs::read_to_string(self.accessed_path())
+            .ok()
+            .and_then(|a| a.trim().parse().ok())
+            .or_else(|| self.completion().map(|c| c.completed_at))
+            .unwrap_or(0)
+    }
+
+    pub fn completion_path(&self) -> PathBuf {
+        self.path.join("complete.json")
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.path.join("manifest.json")
+    }
+
+    pub fn read_manifest(&self) -> io::Result<Manifest> {
+        let file = fs::File::open(self.manifest_path())?;
+        Ok(serde_json::from_reader(io::BufReader::new(file))?)
+    }
+
+    pub fn read_metadata(&self) -> io::Result<DerivationMetadata> {
+        let file = fs::File::open(self.metadata_path())?;
+        Ok(serde_json::from_reader(io::BufReader::new(file))?)
+    }
+
+    pub fn write_metadata(
+        &self,
+        metadata: &DerivationMetadata,
+    ) -> io::Result<()> {
+        write_json(&self.metadata_path(), metadata)
+    }
+
+    pub fn set_status(&self, status: Status) -> io::Result<()> {
+        let mut metadata = self.read_metadata()?;
+        metadata.status = status;
+        self.write_metadata(&metadata)
+    }
+
+    /// Record that the script exited with `exit_status` and everything it
+    /// wrote is on disk, marking the entry as finished and its `out` as
+    /// read-only
+    pub fn complete(
+        &self,
+        exit_status: i32,
+        inputs: BTreeMap<String, String>,
+    ) -> io::Result<Completion> {
+        let manifest = Manifest::of(&self.out(), true)?;
+        let completion = Completion {
+            exit_status,
+            out_checksum: manifest.checksum(),
+            completed_at: now(),
+            reused: None,
+            inputs,
+        };
+        make_read_only(&self.out())?;
+        write_json(&self.manifest_path(), &manifest)?;
+        write_json(&self.completion_path(), &completion)?;
+        self.set_status(Status::Finished)?;
+        Ok(completion)
+    }
+
+    /// Differences between `out` and the manifest recorded when it
+    /// finished, fails for entries without a manifest
+    pub fn verify(&self) -> io::Result<Vec<Mismatch>> {
+        self.compare(&self.out())
+    }
+
+    /// Differences between the manifest recorded when the entry finished
+    /// and the tree at `out`
+    pub fn compare(&self, out: &Path) -> io::Result<Vec<Mismatch>> {
+        let recorded = self.read_manifest()?;
+        Ok(recorded.compare(&Manifest::of(out, false)?))
+    }
+
+    /// true if `out` still matches its manifest, false for entries that
+    /// never finished
+    pub fn is_intact(&self) -> io::Result<bool> {
+        if !self.is_finished() {
+            return Ok(false);
+        }
+        Ok(self.verify()?.is_empty())
+    }
+
+    pub fn completion(&self) -> Option<Completion> {
+        let file = fs::File::open(self.completion_path()).ok()?;
+        serde_json::from_reader(io::BufReader::new(file)).ok()
+    }
+
+    /// true if the derivation ran to completion and can be reused
+    pub fn is_finished(&self) -> bool {
+        self.completion().is_some()
+            && self
+                .read_metadata()
+                .is_ok_and(|m| m.status == Status::Finished)
+    }
+}
+
+/// Write `value` as pretty printed json, replacing the file atomically so
+/// readers never see a partial file
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    let file = fs::File::create(&tmp)?;
+    serde_json::to_writer_pretty(&file, value)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Running,
+    Finished,
+    Failed,
+    Interrupted,
+    /// finished, but mustn't be reused anymore (e.g. `out` was corrupted)
+    Invalid,
+}
+
+/// Contents of `complete.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Completion {
+    pub exit_status: i32,
+    /// [`Manifest::checksum`] of `out`
+    pub out_checksum: String,
+    /// seconds since the unix epoch
+    pub completed_at: u64,
+    /// the entry whose `out` was reused instead of running the script, see
+    /// [`realisations`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reused: Option<String>,
+    /// `out_checksum` of every input it was built against, by hash
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub inputs: BTreeMap<String, String>,
+}
+
+/// Contents of `derivation.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DerivationMetadata {
+    pub name: String,
+    /// names of other processes with the same hash
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    pub hash: String,
+    pub attributes: BTreeMap<String, serde_json::Value>,
+    pub inputs: Vec<String>,
+    pub script: String,
+    pub status: Status,
+}
@@ -0,0 +1,111 @@
+//! Cache of the contents hashes of files read by `File` derivations.
+//!
+//! Hashing large inputs on every evaluation is slow, so the SHA-256 of a
+//! file is kept in `.file-hashes/<sha256 of its path>.json` together with
+//! the inode, size and modification time it had when it was read. As long as
+//! none of those changed the file isn't read again.
+use super::{Store, write_json};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// What has to stay the same for a cached hash to be reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileStamp {
+    pub fn of(metadata: &fs::Metadata) -> Self {
+        Self {
+            inode: metadata.ino(),
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedHash {
+    path: PathBuf,
+    #[serde(flatten)]
+    stamp: FileStamp,
+    sha256: String,
+}
+
+impl Store {
+    fn file_hashes_dir(&self) -> PathBuf {
+        self.root.join(".file-hashes")
+    }
+
+    fn file_hash_path(&self, path: &Path) -> PathBuf {
+        let name = Sha256::digest(path.as_os_str().as_bytes());
+        self.file_hashes_dir().join(format!("{:x}.json", name))
+    }
+
+    /// The SHA-256 recorded for the absolute `path`, if the file still has
+    /// the same `stamp`
+    pub fn cached_file_hash(
+        &self,
+        path: &Path,
+        stamp: &FileStamp,
+    ) -> Option<String> {
+        let file = fs::File::open(self.file_hash_path(path)).ok()?;
+        let cached: CachedHash = serde_json::from_reader(file).ok()?;
+        (cached.path == path && cached.stamp == *stamp).then_some(cached.sha256)
+    }
+
+    pub fn record_file_hash(
+        &self,
+        path: &Path,
+        stamp: &FileStamp,
+        sha256: &str,
+    ) -> io::Result<()> {
+        fs::create_dir_all(self.file_hashes_dir())?;
+        write_json(
+            &self.file_hash_path(path),
+            &CachedHash {
+                path: path.to_path_buf(),
+                stamp: *stamp,
+                sha256: sha256.to_string(),
+            },
+        )
+    }
+
+    /// Delete cached hashes of files that changed or are gone, returns how
+    /// many
+    pub fn prune_file_hashes(&self) -> io::Result<usize> {
+        let cached = match fs::read_dir(self.file_hashes_dir()) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut pruned = 0;
+        for entry in cached {
+            let path = entry?.path();
+            let current = fs::File::open(&path)
+                .ok()
+                .and_then(|f| serde_json::from_reader::<_, CachedHash>(f).ok())
+                .is_some_and(|c| {
+                    fs::metadata(&c.path)
+                        .is_ok_and(|m| FileStamp::of(&m) == c.stamp)
+                });
+            if !current {
+                match fs::remove_file(&path) {
+                    Ok(()) => pruned += 1,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(pruned)
+    }
+}
@@ -0,0 +1,93 @@
+//! Store entries of `File` derivations.
+//!
+//! Scripts reach their inputs through the `run/<hash>` symlinks to
+//! `<workDir>/<hash>/out`, so files and directories read by a pipeline are
+//! copied to the `out` of an entry named after their hash before anything
+//! depending on them runs. Files above `cacheFilesizeCutoff`, which are
+//! usually hashed by their timestamp, are only symlinked to save copying
+//! them. Only the link is read-only, scripts outside of the sandbox can
+//! still write to the original through it. On filesystems that support it, files are cloned (reflinked)
+//! instead of copied, which takes no time or space. They aren't hard linked,
+//! since making the entry read-only would change the original's permissions
+//! and editing the original would change the entry.
+use super::{DerivationMetadata, Store, StoreEntry, remove_tree};
+use crate::derivation_graph::derivation::DerivationHash;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+impl Store {
+    /// Make the entry described by `metadata` hold the file or directory
+    /// `source`, restricted to `files` (relative to `source`) if given.
+    /// Files larger than `link_above` bytes are symlinked. Does nothing if
+    /// the entry exists already. The caller holds the lock of the entry, see
+    /// [`Store::try_lock`].
+    pub fn add_file(
+        &self,
+        metadata: &DerivationMetadata,
+        source: &Path,
+        files: Option<&[PathBuf]>,
+        link_above: Option<u64>,
+    ) -> io::Result<StoreEntry> {
+        let hash = DerivationHash::from(metadata.hash.as_str());
+        let entry = self.entry(&hash);
+        if entry.is_finished() {
+            entry.touch()?;
+            return Ok(entry);
+        }
+        let build = self.build_entry(&hash);
+        remove_tree(&build.path)?;
+        fs::create_dir_all(&build.path)?;
+        build.write_metadata(metadata)?;
+
+        let out = build.out();
+        match files {
+            None => place(source, &out, link_above)?,
+            Some(files) => {
+                fs::create_dir(&out)?;
+                for file in files {
+                    let target = out.join(file);
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    place(&source.join(file), &target, link_above)?;
+                }
+            }
+        }
+        build.complete(0, BTreeMap::new())?;
+        let entry = self.commit(&build, &hash)?;
+        entry.touch()?;
+        Ok(entry)
+    }
+}
+
+fn place(
+    source: &Path,
+    target: &Path,
+    link_above: Option<u64>,
+) -> io::Result<()> {
+    let size = fs::metadata(source)?.len();
+    if link_above.is_some_and(|l| size > l) {
+        symlink(fs::canonicalize(source)?, target)
+    } else {
+        clone_or_copy(source, target)
+    }
+}
+
+/// Clone `source` to `target` if the filesystem supports it, copy it
+/// otherwise
+fn clone_or_copy(source: &Path, target: &Path) -> io::Result<()> {
+    let from = fs::File::open(source)?;
+    let to = fs::File::create(target)?;
+    // SAFETY: both descriptors are open for the duration of the call
+    let cloned =
+        unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) };
+    if cloned == 0 {
+        return to.set_permissions(from.metadata()?.permissions());
+    }
+    drop(to);
+    fs::copy(source, target).map(|_| ())
+}
@@ -0,0 +1,281 @@
+//! Garbage collection of the store.
+//!
+//! An entry is live if it can be reached from a root through the `inputs` in
+//! its `derivation.json`. Roots are the derivations of the current pipeline,
+//! results pinned with `piper pin` (`.roots/<hash>`) and the derivations of
+//! every piper process that is running right now (`.runs/<host>.<pid>`).
+//! Everything else is garbage. Entries are deleted while holding their lock,
+//! so one that another piper process is about to build or reuse is skipped.
+use super::{Store, StoreEntry, hostname, owner_tag, process_alive};
+use crate::derivation_graph::derivation::DerivationHash;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+impl Store {
+    fn roots_dir(&self) -> PathBuf {
+        self.root.join(".roots")
+    }
+
+    fn runs_dir(&self) -> PathBuf {
+        self.root.join(".runs")
+    }
+
+    /// Keep `hash` and everything it depends on until it is unpinned
+    pub fn pin(&self, hash: &DerivationHash) -> io::Result<()> {
+        fs::create_dir_all(self.roots_dir())?;
+        fs::File::create(self.roots_dir().join(hash.to_string()))?;
+        Ok(())
+    }
+
+    /// Returns false if `hash` wasn't pinned
+    pub fn unpin(&self, hash: &DerivationHash) -> io::Result<bool> {
+        match fs::remove_file(self.roots_dir().join(hash.to_string())) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn pinned(&self) -> io::Result<Vec<DerivationHash>> {
+        Ok(list_dir(&self.roots_dir())?
+            .into_iter()
+            .map(|name| name.as_str().into())
+            .collect())
+    }
+
+    /// Register the derivations a run of this process needs as roots, they
+    /// stop being roots when the returned guard is dropped
+    pub fn register_run<'a>(
+        &self,
+        hashes: impl IntoIterator<Item = &'a DerivationHash>,
+    ) -> io::Result<RunRoots> {
+        fs::create_dir_all(self.runs_dir())?;
+        let path = self.runs_dir().join(owner_tag());
+        let hashes: Vec<String> =
+            hashes.into_iter().map(|h| h.to_string()).collect();
+        fs::write(&path, hashes.join("\n"))?;
+        Ok(RunRoots { path })
+    }
+
+    /// Roots registered by piper processes that are still running. Those of
+    /// other hosts can't be checked and are always kept.
+    fn running(&self) -> io::Result<Vec<DerivationHash>> {
+        let mut hashes = Vec::new();
+        let host = hostname();
+        for name in list_dir(&self.runs_dir())? {
+            let path = self.runs_dir().join(&name);
+            let local_pid = match name.rsplit_once('.') {
+                Some((h, pid)) if h == host => Some(pid),
+                Some(_) => None,
+                // registered by a piper version that didn't name the host
+                None => Some(name.as_str()),
+            };
+            if local_pid.is_some_and(|pid| !process_alive(pid)) {
+                let _ = fs::remove_file(path);
+                continue;
+            }
+            let contents = match fs::read_to_string(&path) {
+                Ok(c) => c,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            hashes.extend(contents.lines().map(DerivationHash::from));
+        }
+        Ok(hashes)
+    }
+
+    /// Every derivation in the store
+    pub fn entries(&self) -> io::Result<Vec<(DerivationHash, StoreEntry)>> {
+        Ok(list_dir(&self.root)?
+            .into_iter()
+            .map(|name| {
+                let hash = DerivationHash::from(name.as_str());
+                let entry = self.entry(&hash);
+                (hash, entry)
+            })
+            .collect())
+    }
+
+    /// `roots`, pinned and running derivations and everything they depend
+    /// on
+    pub fn live(
+        &self,
+        roots: impl IntoIterator<Item = DerivationHash>,
+    ) -> io::Result<HashSet<DerivationHash>> {
+        let mut stack: Vec<DerivationHash> = roots.into_iter().collect();
+        stack.extend(self.pinned()?);
+        stack.extend(self.running()?);
+        let mut live = HashSet::new();
+        while let Some(hash) = stack.pop() {
+            if live.contains(&hash) {
+                continue;
+            }
+            if let Ok(metadata) = self.entry(&hash).read_metadata() {
+                stack.extend(
+                    metadata
+                        .inputs
+                        .iter()
+                        .map(|i| DerivationHash::from(i.as_str())),
+                );
+            }
+            live.insert(hash);
+        }
+        Ok(live)
+    }
+
+    /// Delete every entry that isn't `live`, or only list them if `dry_run`
+    /// is set. Returns the collected entries with the bytes deleting them
+    /// frees, files shared with other entries through `.links` aren't
+    /// counted.
+    pub fn collect_garbage(
+        &self,
+        live: &HashSet<DerivationHash>,
+        dry_run: bool,
+    ) -> io::Result<Vec<(DerivationHash, u64)>> {
+        let mut collected = Vec::new();
+        for (hash, entry) in self.entries()? {
+            if live.contains(&hash) {
+                continue;
+            }
+            let size = unshared_size(&entry.path)?;
+            let removed = if dry_run {
+                self.try_lock(&hash)?.is_ok()
+            } else {
+                self.remove_unlocked(&hash, &entry)?
+            };
+            if removed {
+                collected.push((hash, size));
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Delete `entry` while holding its lock. Returns false if another
+    /// piper process holds it, i.e. builds or reuses the entry, and it was
+    /// left alone.
+    fn remove_unlocked(
+        &self,
+        hash: &DerivationHash,
+        entry: &StoreEntry,
+    ) -> io::Result<bool> {
+        let Ok(_lock) = self.try_lock(hash)? else {
+            return Ok(false);
+        };
+        self.remove(entry)?;
+        Ok(true)
+    }
+}
+
+impl Store {
+    /// Delete entries that aren't `live`, least recently used first, until
+    /// the store takes up at most `max_bytes`. Returns the evicted entries
+    /// and the size of the store afterwards.
+    pub fn evict(
+        &self,
+        live: &HashSet<DerivationHash>,
+        max_bytes: u64,
+    ) -> io::Result<(Vec<DerivationHash>, u64)> {
+        let mut total = disk_usage(&self.root, &mut HashSet::new())?;
+        let mut evicted = Vec::new();
+        let mut candidates: Vec<(u64, DerivationHash, StoreEntry)> = self
+            .entries()?
+            .into_iter()
+            .filter(|(hash, _)| !live.contains(hash))
+            .map(|(hash, entry)| (entry.last_access(), hash, entry))
+            .collect();
+        candidates.sort_by_key(|(accessed, _, _)| Reverse(*accessed));
+        // files shared through `.links` are only freed once their last
+        // entry is gone, so the store is measured again after each round
+        while total > max_bytes && !candidates.is_empty() {
+            let mut estimate = total;
+            while estimate > max_bytes {
+                let Some((_, hash, entry)) = candidates.pop() else {
+                    break;
+                };
+                let size = unshared_size(&entry.path)?;
+                if self.remove_unlocked(&hash, &entry)? {
+                    estimate = estimate.saturating_sub(size);
+                    evicted.push(hash);
+                }
+            }
+            self.prune_links()?;
+            total = disk_usage(&self.root, &mut HashSet::new())?;
+        }
+        Ok((evicted, total))
+    }
+}
+
+/// Removes the roots of a run from the store when dropped
+pub struct RunRoots {
+    path: PathBuf,
+}
+
+impl Drop for RunRoots {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Names in `dir` that don't start with a `.`, nothing if it doesn't exist
+fn list_dir(dir: &Path) -> io::Result<Vec<String>> {
+    let children = match fs::read_dir(dir) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut names = Vec::new();
+    for child in children {
+        let name = child?.file_name().to_string_lossy().to_string();
+        if !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Bytes allocated for `path`, files already in `seen` (hard links) are
+/// only counted once
+pub fn disk_usage(
+    path: &Path,
+    seen: &mut HashSet<(u64, u64)>,
+) -> io::Result<u64> {
+    measure(path, &mut |m| seen.insert((m.dev(), m.ino())))
+}
+
+/// Bytes freed by deleting `path`, files with hard links elsewhere aren't
+/// counted
+pub fn unshared_size(path: &Path) -> io::Result<u64> {
+    measure(path, &mut |m| m.is_dir() || m.nlink() == 1)
+}
+
+/// Sum of the blocks of everything in `path` that `count` accepts
+fn measure(
+    path: &Path,
+    count: &mut impl FnMut(&fs::Metadata) -> bool,
+) -> io::Result<u64> {
+    // other piper processes may delete entries while they are measured
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut size = 0;
+    if count(&metadata) {
+        size += metadata.blocks() * 512;
+    }
+    if metadata.is_dir() {
+        let children = match fs::read_dir(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(size),
+            Err(e) => return Err(e),
+        };
+        for child in children {
+            size += measure(&child?.path(), count)?;
+        }
+    }
+    Ok(size)
+}
@@ -0,0 +1,308 @@
+//! Locks keeping concurrent piper processes from building the same entry.
+//!
+//! Before a derivation is built, `.locks/<hash>` is created with `O_EXCL`,
+//! which is atomic on local filesystems as well as on NFS and Lustre, unlike
+//! `flock` and `fcntl` locks that depend on how those are mounted. The file
+//! names the host and process holding it. Other piper processes wait until
+//! it is gone and then reuse the finished entry.
+//!
+//! A lock is stale if its holder died without removing it. On the same host
+//! that is checked with the process id; processes on other hosts can't be
+//! seen, so a thread refreshes the modification time of held locks every
+//! [`LOCK_REFRESH`], however long the build blocks, and locks that weren't
+//! refreshed for [`LOCK_TIMEOUT`] are broken. The timeout is generous to
+//! tolerate clock differences between nodes.
+use super::{Store, hostname, now, process_alive};
+use crate::derivation_graph::derivation::DerivationHash;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// How often held locks are refreshed
+pub static LOCK_REFRESH: Duration = Duration::from_secs(60);
+
+/// How long a lock held on another host may go without being refreshed
+pub static LOCK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How often a derivation waiting for a lock checks whether it was
+/// released
+pub static LOCK_POLL: Duration = Duration::from_secs(1);
+
+/// Contents of `.locks/<hash>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockHolder {
+    pub host: String,
+    pub pid: u32,
+    /// seconds since the unix epoch
+    pub acquired_at: u64,
+}
+
+impl LockHolder {
+    fn this_process() -> Self {
+        Self {
+            host: hostname(),
+            pid: std::process::id(),
+            acquired_at: now(),
+        }
+    }
+}
+
+impl std::fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "process {} on {}", self.pid, self.host)
+    }
+}
+
+/// A held lock, released when dropped
+#[derive(Debug)]
+pub struct BuildLock {
+    path: PathBuf,
+    holder: LockHolder,
+    /// dropping it stops the refresher
+    stop: Option<mpsc::Sender<()>>,
+    refresher: Option<JoinHandle<()>>,
+}
+
+impl BuildLock {
+    fn new(path: PathBuf, holder: LockHolder) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let refreshed = path.clone();
+        let refresher = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(LOCK_REFRESH)
+            {
+                refresh(&refreshed);
+            }
+        });
+        Self {
+            path,
+            holder,
+            stop: Some(stop),
+            refresher: Some(refresher),
+        }
+    }
+}
+
+/// Update the modification time of the lock at `path`, so piper processes
+/// on other hosts don't take it for stale
+fn refresh(path: &Path) {
+    let refreshed = fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.set_modified(SystemTime::now()));
+    if let Err(e) = refreshed {
+        println!("couldn't refresh {}: {}", path.display(), e);
+    }
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(refresher) = self.refresher.take() {
+            let _ = refresher.join();
+        }
+        // the lock may have been broken and taken by another process
+        if read_holder(&self.path).is_some_and(|h| h == self.holder) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Store {
+    fn locks_dir(&self) -> PathBuf {
+        self.root.join(".locks")
+    }
+
+    /// Take the lock of `hash` if nobody holds it, otherwise returns who
+    /// does. Stale locks are broken.
+    pub fn try_lock(
+        &self,
+        hash: &DerivationHash,
+    ) -> io::Result<Result<BuildLock, Option<LockHolder>>> {
+        fs::create_dir_all(self.locks_dir())?;
+        let path = self.locks_dir().join(hash.to_string());
+        // a second attempt after breaking a stale lock
+        for _ in 0..2 {
+            match fs::File::options().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let holder = LockHolder::this_process();
+                    file.write_all(&serde_json::to_vec(&holder)?)?;
+                    file.sync_all()?;
+                    return Ok(Ok(BuildLock::new(path, holder)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            let holder = read_holder(&path);
+            if !self.is_stale(&path, holder.as_ref()) {
+                return Ok(Err(holder));
+            }
+            self.break_lock(&path, holder.as_ref())?;
+        }
+        Ok(Err(read_holder(&path)))
+    }
+
+    /// true if the lock at `path` held by `holder` (unknown if its contents
+    /// couldn't be read yet) was abandoned
+    fn is_stale(&self, path: &Path, holder: Option<&LockHolder>) -> bool {
+        if let Some(holder) = holder {
+            if holder.host == hostname() {
+                return !process_alive(&holder.pid.to_string());
+            }
+        }
+        fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|m| SystemTime::now().duration_since(m).ok())
+            .is_some_and(|age| age > LOCK_TIMEOUT)
+    }
+
+    /// Remove the stale lock at `path`. It is renamed first, so of several
+    /// processes breaking it at once only one succeeds, and put back if it
+    /// was taken by someone else in the meantime.
+    fn break_lock(
+        &self,
+        path: &Path,
+        holder: Option<&LockHolder>,
+    ) -> io::Result<()> {
+        let broken = path.with_extension(format!(
+            "broken.{}.{}",
+            hostname(),
+            std::process::id()
+        ));
+        match fs::rename(path, &broken) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        if read_holder(&broken).as_ref() != holder {
+            // fails if yet another process took the lock, which is fine
+            let _ = fs::hard_link(&broken, path);
+        } else {
+            println!(
+                "broke stale lock {}{}",
+                path.display(),
+                holder.map_or(String::new(), |h| format!(" of {}", h))
+            );
+        }
+        fs::remove_file(&broken)
+    }
+}
+
+fn read_holder(path: &Path) -> Option<LockHolder> {
+    let contents = fs::read(path).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::remove_tree;
+
+    fn temp_store(name: &str) -> Store {
+        let dir = std::env::temp_dir().join(format!(
+            "piper-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = remove_tree(&dir);
+        Store::new(dir)
+    }
+
+    /// Write a lock of `holder` for `hash` as if another process held it
+    fn plant(store: &Store, hash: &DerivationHash, holder: &LockHolder) {
+        fs::create_dir_all(store.locks_dir()).expect("couldn't create .locks");
+        let path = store.locks_dir().join(hash.to_string());
+        let holder = serde_json::to_vec(holder).expect("couldn't serialize");
+        fs::write(path, holder).expect("couldn't write lock");
+    }
+
+    fn try_lock(
+        store: &Store,
+        hash: &DerivationHash,
+    ) -> Result<BuildLock, Option<LockHolder>> {
+        store.try_lock(hash).expect("couldn't lock")
+    }
+
+    fn holder(host: &str, pid: u32) -> LockHolder {
+        LockHolder {
+            host: host.to_string(),
+            pid,
+            acquired_at: now(),
+        }
+    }
+
+    #[test]
+    fn held_until_dropped() {
+        let store = temp_store("lock-held");
+        let hash = DerivationHash::from("a");
+        let lock = try_lock(&store, &hash).expect("lock not taken");
+        let other = try_lock(&store, &hash).err().flatten();
+        let other = other.expect("lock taken twice");
+        assert_eq!((other.host, other.pid), (hostname(), std::process::id()));
+        drop(lock);
+        assert!(!store.locks_dir().join("a").exists());
+        assert!(try_lock(&store, &hash).is_ok());
+        let _ = remove_tree(&store.root);
+    }
+
+    #[test]
+    fn breaks_lock_of_dead_process() {
+        let store = temp_store("lock-dead");
+        let hash = DerivationHash::from("a");
+        let mut child = std::process::Command::new("true")
+            .spawn()
+            .expect("couldn't start true");
+        child.wait().expect("couldn't wait for true");
+        plant(&store, &hash, &holder(&hostname(), child.id()));
+        assert!(try_lock(&store, &hash).is_ok());
+        let _ = remove_tree(&store.root);
+    }
+
+    #[test]
+    fn breaks_lock_of_other_host_once_not_refreshed() {
+        let store = temp_store("lock-host");
+        let hash = DerivationHash::from("a");
+        let elsewhere = holder("elsewhere", 1);
+        plant(&store, &hash, &elsewhere);
+        let held = try_lock(&store, &hash);
+        assert_eq!(held.err(), Some(Some(elsewhere)));
+
+        let path = store.locks_dir().join("a");
+        let abandoned = SystemTime::now() - LOCK_TIMEOUT - LOCK_REFRESH;
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(abandoned))
+            .expect("couldn't age lock");
+        assert!(try_lock(&store, &hash).is_ok());
+        let _ = remove_tree(&store.root);
+    }
+
+    #[test]
+    fn break_lock_keeps_lock_taken_meanwhile() {
+        let store = temp_store("lock-taken");
+        let hash = DerivationHash::from("a");
+        let path = store.locks_dir().join("a");
+        let taken = holder("elsewhere", 2);
+        plant(&store, &hash, &taken);
+        store
+            .break_lock(&path, Some(&holder("elsewhere", 1)))
+            .expect("couldn't break lock");
+        assert_eq!(read_holder(&path), Some(taken.clone()));
+
+        store
+            .break_lock(&path, Some(&taken))
+            .expect("couldn't break lock");
+        assert!(!path.exists());
+        // already broken by another process
+        store
+            .break_lock(&path, Some(&taken))
+            .expect("couldn't break lock");
+        let _ = remove_tree(&store.root);
+    }
+}
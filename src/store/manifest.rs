@@ -0,0 +1,159 @@
+//! Manifests of `out` trees.
+//!
+//! When an entry finishes, the SHA-256 of every file in its `out` is recorded
+//! in `manifest.json` along with the targets of symlinks and the executable
+//! bit, which only depend on what is in the tree. `piper verify` compares
+//! entries against their manifest to find corrupted or modified outputs. The
+//! `out_checksum` in `complete.json` is the checksum of the manifest.
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// What is at a path below `out`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Node {
+    File {
+        sha256: String,
+        size: u64,
+        executable: bool,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+    Directory,
+}
+
+/// Every path below `out`, relative to it. `out` itself is the empty path,
+/// the manifest is empty if the script didn't write an output.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub nodes: BTreeMap<PathBuf, Node>,
+}
+
+/// A difference between a manifest and what is on disk
+#[derive(Debug)]
+pub enum Mismatch {
+    Missing(PathBuf),
+    Modified(PathBuf),
+    Unexpected(PathBuf),
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (kind, path) = match self {
+            Mismatch::Missing(v) => ("missing", v),
+            Mismatch::Modified(v) => ("modified", v),
+            Mismatch::Unexpected(v) => ("unexpected", v),
+        };
+        if path.as_os_str().is_empty() {
+            write!(f, "{}: out", kind)
+        } else {
+            write!(f, "{}: out/{}", kind, path.display())
+        }
+    }
+}
+
+impl Manifest {
+    /// Read the tree at `out`. With `sync` every file is flushed to disk
+    /// while it is read, so a manifest is never recorded for data that
+    /// could still be lost in a crash.
+    pub fn of(out: &Path, sync: bool) -> io::Result<Manifest> {
+        let mut manifest = Manifest::default();
+        match fs::symlink_metadata(out) {
+            Ok(_) => manifest.add(out, PathBuf::new(), sync)?,
+            // the script didn't write any output
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(manifest)
+    }
+
+    fn add(
+        &mut self,
+        path: &Path,
+        relative: PathBuf,
+        sync: bool,
+    ) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        let node = if metadata.is_symlink() {
+            Node::Symlink {
+                target: fs::read_link(path)?,
+            }
+        } else if metadata.is_dir() {
+            for child in fs::read_dir(path)? {
+                let name = child?.file_name();
+                self.add(&path.join(&name), relative.join(&name), sync)?;
+            }
+            if sync {
+                fs::File::open(path)?.sync_all()?;
+            }
+            Node::Directory
+        } else {
+            let mut hasher = sha2::Sha256::new();
+            let mut file = fs::File::open(path)?;
+            let size = io::copy(&mut file, &mut hasher)?;
+            if sync {
+                file.sync_all()?;
+            }
+            Node::File {
+                sha256: format!("{:x}", hasher.finalize()),
+                size,
+                executable: metadata.permissions().mode() & 0o111 != 0,
+            }
+        };
+        self.nodes.insert(relative, node);
+        Ok(())
+    }
+
+    /// Checksum of the whole tree
+    pub fn checksum(&self) -> String {
+        let mut hasher = sha2::Sha256::new();
+        for (path, node) in &self.nodes {
+            hasher.update(path.as_os_str().as_encoded_bytes());
+            hasher.update(b"\0");
+            match node {
+                Node::File {
+                    sha256,
+                    size,
+                    executable,
+                } => {
+                    hasher.update(if *executable { b"x" } else { b"f" });
+                    hasher.update(size.to_le_bytes());
+                    hasher.update(sha256.as_bytes());
+                }
+                Node::Symlink { target } => {
+                    hasher.update(b"l");
+                    hasher.update(target.as_os_str().as_encoded_bytes());
+                }
+                Node::Directory => hasher.update(b"d"),
+            }
+            hasher.update(b"\0");
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Differences between this manifest and `actual`, what is on disk now
+    pub fn compare(&self, actual: &Manifest) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        for (path, node) in &self.nodes {
+            match actual.nodes.get(path) {
+                None => mismatches.push(Mismatch::Missing(path.clone())),
+                Some(v) if v != node => {
+                    mismatches.push(Mismatch::Modified(path.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for path in actual.nodes.keys() {
+            if !self.nodes.contains_key(path) {
+                mismatches.push(Mismatch::Unexpected(path.clone()));
+            }
+        }
+        mismatches
+    }
+}
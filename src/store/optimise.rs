@@ -0,0 +1,160 @@
+//! Deduplication of identical files in the store.
+//!
+//! Every regular file in the `out` of a finished entry is hashed and hard
+//! linked to a blob in `.links/` named after its SHA-256 (with `-x` appended
+//! for executables, since links share permissions). Files with the same
+//! contents end up as the same inode, paths don't change. Entries that were
+//! deduplicated get an `optimised` marker so they aren't hashed again.
+use super::{Store, StoreEntry};
+use sha2::Digest;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// What deduplicating some entries saved
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Savings {
+    pub linked_files: usize,
+    pub bytes: u64,
+}
+
+impl Store {
+    fn links_dir(&self) -> PathBuf {
+        self.root.join(".links")
+    }
+
+    /// Replace the files in `out` of `entry` that are already in the store
+    /// with hard links. Entries that aren't finished are left alone.
+    pub fn optimise(&self, entry: &StoreEntry) -> io::Result<Savings> {
+        let mut savings = Savings::default();
+        if !entry.is_finished() || entry.optimised_path().exists() {
+            return Ok(savings);
+        }
+        fs::create_dir_all(self.links_dir())?;
+        self.optimise_path(&entry.out(), &mut savings)?;
+        fs::File::create(entry.optimised_path())?;
+        Ok(savings)
+    }
+
+    fn optimise_path(
+        &self,
+        path: &Path,
+        savings: &mut Savings,
+    ) -> io::Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if metadata.is_dir() {
+            for child in fs::read_dir(path)? {
+                self.optimise_path(&child?.path(), savings)?;
+            }
+        } else if metadata.is_file() && metadata.len() > 0 {
+            let saved = self.link(path, &metadata)?;
+            if let Some(bytes) = saved {
+                savings.linked_files += 1;
+                savings.bytes += bytes;
+            }
+        }
+        Ok(())
+    }
+
+    /// Link `path` to the blob with its contents, returns the bytes freed
+    /// if it was replaced
+    fn link(
+        &self,
+        path: &Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<Option<u64>> {
+        let mut hasher = sha2::Sha256::new();
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        let executable = metadata.permissions().mode() & 0o111 != 0;
+        let blob = self.links_dir().join(format!(
+            "{:x}{}",
+            hasher.finalize(),
+            if executable { "-x" } else { "" }
+        ));
+
+        match fs::symlink_metadata(&blob) {
+            Ok(b) if b.dev() == metadata.dev() && b.ino() == metadata.ino() => {
+                return Ok(None);
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // the first copy becomes the blob
+                return match fs::hard_link(path, &blob) {
+                    Ok(()) => Ok(None),
+                    Err(e) if too_many_links(&e) => Ok(None),
+                    Err(e) => Err(e),
+                };
+            }
+            Err(e) => return Err(e),
+        }
+
+        let Some(dir) = path.parent() else {
+            return Ok(None);
+        };
+        let tmp = dir.join(format!(".piper-link.{}", std::process::id()));
+        let linked = with_writable_dir(dir, || {
+            fs::hard_link(&blob, &tmp)?;
+            // replaces `path` atomically, readers never see it missing
+            fs::rename(&tmp, path).inspect_err(|_| {
+                let _ = fs::remove_file(&tmp);
+            })
+        });
+        match linked {
+            Ok(()) => {}
+            Err(e) if too_many_links(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        // files with other links (e.g. in another entry) aren't freed
+        Ok(Some(if metadata.nlink() == 1 {
+            metadata.blocks() * 512
+        } else {
+            0
+        }))
+    }
+
+    /// Delete blobs no entry links to anymore, returns how many were
+    /// deleted and their size in bytes
+    pub fn prune_links(&self) -> io::Result<(usize, u64)> {
+        let blobs = match fs::read_dir(self.links_dir()) {
+            Ok(b) => b,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+            Err(e) => return Err(e),
+        };
+        let (mut count, mut bytes) = (0, 0);
+        for blob in blobs {
+            let blob = blob?;
+            let metadata = blob.metadata()?;
+            if metadata.nlink() == 1 {
+                fs::remove_file(blob.path())?;
+                count += 1;
+                bytes += metadata.blocks() * 512;
+            }
+        }
+        Ok((count, bytes))
+    }
+}
+
+fn too_many_links(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMLINK)
+}
+
+/// Run `f` with write permission on `dir`, which is read-only in finished
+/// entries
+fn with_writable_dir<T>(
+    dir: &Path,
+    f: impl FnOnce() -> io::Result<T>,
+) -> io::Result<T> {
+    let mode = fs::metadata(dir)?.permissions().mode();
+    if mode & 0o200 != 0 {
+        return f();
+    }
+    fs::set_permissions(dir, fs::Permissions::from_mode(mode | 0o200))?;
+    let result = f();
+    fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+    result
+}
@@ -0,0 +1,137 @@
+//! Content addressed reuse of outputs (early cutoff).
+//!
+//! The hash of a process includes the hashes of its inputs, so any change
+//! upstream reruns everything downstream, even when the upstream output is
+//! byte for byte the same as before. Processes with `contentAddressed : #t`
+//! are also known by a resolved hash, computed with the checksums of their
+//! inputs' outputs in place of the inputs' hashes. When such a process
+//! finishes, `.realisations/<resolved hash>` records which entry was built.
+//! A later process with the same resolved hash reuses that entry's `out`
+//! through hard links instead of running.
+use super::{Completion, DerivationMetadata, Status, Store, StoreEntry, now};
+use super::{make_read_only, remove_tree, write_json};
+use crate::derivation_graph::derivation::DerivationHash;
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+impl Store {
+    fn realisation_path(&self, resolved: &DerivationHash) -> PathBuf {
+        self.root.join(".realisations").join(resolved.to_string())
+    }
+
+    /// The finished entry that was built with the resolved hash `resolved`
+    pub fn realisation(
+        &self,
+        resolved: &DerivationHash,
+    ) -> Option<DerivationHash> {
+        let hash = fs::read_to_string(self.realisation_path(resolved)).ok()?;
+        let hash = DerivationHash::from(hash.trim());
+        self.entry(&hash).is_finished().then_some(hash)
+    }
+
+    /// Record that `hash` was built with the resolved hash `resolved`
+    pub fn record_realisation(
+        &self,
+        resolved: &DerivationHash,
+        hash: &DerivationHash,
+    ) -> io::Result<()> {
+        let path = self.realisation_path(resolved);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, hash.to_string())?;
+        fs::rename(tmp, path)
+    }
+
+    /// Delete realisations of entries that are gone, returns how many
+    pub fn prune_realisations(&self) -> io::Result<usize> {
+        let realisations = match fs::read_dir(self.root.join(".realisations")) {
+            Ok(r) => r,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut pruned = 0;
+        for realisation in realisations {
+            let path = realisation?.path();
+            let Ok(hash) = fs::read_to_string(&path) else {
+                continue;
+            };
+            if !self.entry(&hash.trim().into()).is_finished() {
+                fs::remove_file(path)?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Create the entry `metadata` describes from the output of the
+    /// finished entry `source`, without running anything
+    pub fn reuse(
+        &self,
+        metadata: &DerivationMetadata,
+        source_hash: &DerivationHash,
+    ) -> io::Result<StoreEntry> {
+        let hash = DerivationHash::from(metadata.hash.as_str());
+        let source = self.entry(source_hash);
+        let completion = source.completion().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "source isn't finished")
+        })?;
+        let build = self.build_entry(&hash);
+        if build.path.exists() {
+            remove_tree(&build.path)?;
+        }
+        fs::create_dir_all(&build.path)?;
+        build.write_metadata(&DerivationMetadata {
+            status: Status::Running,
+            ..metadata.clone()
+        })?;
+        link_tree(&source.out(), &build.out())?;
+        make_read_only(&build.out())?;
+        fs::copy(source.manifest_path(), build.manifest_path())?;
+        write_json(
+            &build.completion_path(),
+            &Completion {
+                exit_status: completion.exit_status,
+                out_checksum: completion.out_checksum,
+                completed_at: now(),
+                reused: Some(source_hash.to_string()),
+                inputs: self.input_checksums(&metadata.inputs),
+            },
+        )?;
+        build.set_status(Status::Finished)?;
+        let entry = self.commit(&build, &hash)?;
+        let _ = entry.touch();
+        Ok(entry)
+    }
+}
+
+/// Recreate the tree at `source` at `target`, hard linking files
+fn link_tree(source: &Path, target: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(source) {
+        Ok(m) => m,
+        // the source didn't write an output either
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if metadata.is_symlink() {
+        symlink(fs::read_link(source)?, target)
+    } else if metadata.is_dir() {
+        fs::create_dir(target)?;
+        for child in fs::read_dir(source)? {
+            let name = child?.file_name();
+            link_tree(&source.join(&name), &target.join(&name))?;
+        }
+        Ok(())
+    } else {
+        match fs::hard_link(source, target) {
+            // the source has as many links as the filesystem allows
+            Err(e) if e.raw_os_error() == Some(libc::EMLINK) => {
+                fs::copy(source, target).map(|_| ())
+            }
+            v => v,
+        }
+    }
+}
@@ -27,6 +27,17 @@ macro_rules! test_scm_file {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::derivation_graph::derivation::{Derivation, DerivationHash};
+    use crate::derivation_graph::derivation_runner::RunError;
+    use crate::derivation_graph::extract_graph;
+    use crate::store::{Store, remove_tree};
+    use sha2::{Digest, Sha256};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
 
     #[test]
     fn basic_interpolations() {
@@ -43,4 +54,264 @@ mod tests {
     fn cycle_panic() {
         test_scm_file!("steel-modules/tests/cycle_panic.scm");
     }
+
+    /// Engine storing derivations in a fresh directory below the temporary
+    /// directory, along with that directory
+    fn engine_in_temp_dir(name: &str) -> (Engine, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "piper-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("couldn't create test directory");
+        let config = dir.join("config.scm");
+        let work_dir = dir.join("work");
+        fs::write(&config, format!("(config workDir {:?})", work_dir))
+            .expect("couldn't write test config");
+        (engine(Some(config)), dir)
+    }
+
+    /// Fetch `url` in a fresh store, checking it against `sha256`
+    fn fetch_url(name: &str, url: &str, sha256: &str) -> Result<(), RunError> {
+        let (mut e, dir) = engine_in_temp_dir(name);
+        e.run(format!(
+            r#"(define fetched (fetch! "{}" "{}"))
+               (output! "fetched" : fetched)"#,
+            url, sha256
+        ))
+        .expect("Failed Test");
+        let result = extract_graph(&mut e).expect("no graph").run();
+        let _ = remove_tree(&dir);
+        result
+    }
+
+    /// Fetch a file with `contents` through a file:// url, checking it
+    /// against `sha256`
+    fn fetch_file(
+        name: &str,
+        contents: &str,
+        sha256: &str,
+    ) -> Result<(), RunError> {
+        let source = std::env::temp_dir().join(format!(
+            "piper-{}-{}.txt",
+            name,
+            std::process::id()
+        ));
+        fs::write(&source, contents).expect("couldn't write source file");
+        let result =
+            fetch_url(name, &format!("file://{}", source.display()), sha256);
+        let _ = fs::remove_file(&source);
+        result
+    }
+
+    /// Serve `contents` to every request on a localhost port, returns the
+    /// url of the file
+    fn serve(contents: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .expect("couldn't listen on localhost");
+        let address = listener.local_addr().expect("couldn't get address");
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                // the request doesn't matter, only wait until it was sent
+                let mut request = BufReader::new(&stream);
+                let mut line = String::new();
+                while request.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    contents.len(),
+                    contents
+                );
+            }
+        });
+        format!("http://{}/reference.txt", address)
+    }
+
+    #[test]
+    fn fetch_matching_checksum() {
+        let sha256 = format!("{:x}", Sha256::digest("reference\n"));
+        fetch_file("fetch-matching", "reference\n", &sha256)
+            .expect("fetch with the right checksum failed");
+        // the case of the checksum doesn't matter
+        fetch_file("fetch-upper", "reference\n", &sha256.to_uppercase())
+            .expect("fetch with an upper case checksum failed");
+    }
+
+    #[test]
+    fn fetch_over_http() {
+        let sha256 = format!("{:x}", Sha256::digest("reference\n"));
+        let url = serve("reference\n");
+        fetch_url("fetch-http", &url, &sha256)
+            .expect("fetch from a local http server failed");
+        let other = format!("{:x}", Sha256::digest("something else"));
+        let result = fetch_url("fetch-http-mismatching", &url, &other);
+        assert!(matches!(result, Err(RunError::Failed(_))));
+    }
+
+    #[test]
+    fn fetch_mismatching_checksum() {
+        let sha256 = format!("{:x}", Sha256::digest("something else"));
+        let result = fetch_file("fetch-mismatching", "reference\n", &sha256);
+        assert!(matches!(result, Err(RunError::Failed(_))));
+    }
+
+    /// Files below `dir` relative to it, with their contents
+    fn tree(dir: &Path) -> BTreeMap<PathBuf, String> {
+        let mut files = BTreeMap::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(path) = pending.pop() {
+            if !path.is_dir() {
+                files.insert(
+                    path.strip_prefix(dir).expect("outside of dir").to_owned(),
+                    fs::read_to_string(&path).expect("couldn't read file"),
+                );
+                continue;
+            }
+            for child in fs::read_dir(&path).expect("couldn't list dir") {
+                pending.push(child.expect("couldn't list dir").path());
+            }
+        }
+        files
+    }
+
+    /// Build `pipeline` in a fresh store, returns the hash of the process
+    /// called `name`, the checksum of its `out` and the files in it
+    fn build(
+        test: &str,
+        pipeline: &str,
+        name: &str,
+    ) -> (DerivationHash, String, BTreeMap<PathBuf, String>) {
+        let (mut e, dir) = engine_in_temp_dir(test);
+        e.run(pipeline.to_string()).expect("invalid pipeline");
+        let graph = extract_graph(&mut e).expect("no graph");
+        graph.run().expect("couldn't build pipeline");
+        let hash = graph
+            .nodes
+            .values()
+            .find_map(|d| match d {
+                Derivation::Process(p) if p.has_name(name) => {
+                    Some(p.hash.clone())
+                }
+                _ => None,
+            })
+            .expect("no such process");
+        let entry = Store::new(dir.join("work")).entry(&hash);
+        let checksum = entry.completion().expect("not finished").out_checksum;
+        let files = tree(&entry.out());
+        let _ = remove_tree(&dir);
+        (hash, checksum, files)
+    }
+
+    /// Run git in `dir` with an identity, failing the test if it fails
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(["-c", "user.name=piper", "-c", "user.email=piper@localhost"])
+            .args(["-c", "protocol.file.allow=always"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .expect("couldn't run git");
+        assert!(
+            output.status.success(),
+            "git {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    #[test]
+    fn git_checks_out_pinned_commit() {
+        let dir = std::env::temp_dir()
+            .join(format!("piper-git-repositories-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (tools, lib) = (dir.join("tools"), dir.join("lib"));
+        for repository in [&tools, &lib] {
+            fs::create_dir_all(repository).expect("couldn't create repository");
+            git(repository, &["init", "--quiet"]);
+        }
+        fs::write(lib.join("lib.txt"), "lib\n").expect("couldn't write file");
+        git(&lib, &["add", "lib.txt"]);
+        git(&lib, &["commit", "--quiet", "--message", "lib"]);
+        fs::write(tools.join("tool.sh"), "echo 1\n").expect("couldn't write");
+        git(
+            &tools,
+            &["submodule", "--quiet", "add", &lib.to_string_lossy()],
+        );
+        git(&tools, &["add", "tool.sh"]);
+        git(&tools, &["commit", "--quiet", "--message", "tools"]);
+        let commit = git(&tools, &["rev-parse", "HEAD"]);
+        // only the pinned commit is checked out
+        fs::write(tools.join("tool.sh"), "echo 2\n").expect("couldn't write");
+        git(
+            &tools,
+            &["commit", "--quiet", "--all", "--message", "newer"],
+        );
+
+        let pipeline = |submodules: &str| {
+            format!(
+                r#"(define tools
+                     (git! "{}" "{}" #:submodules {}))
+                   (output! "tools" : tools)"#,
+                tools.display(),
+                commit,
+                submodules
+            )
+        };
+        let checkout = build("git", &pipeline("#f"), "git");
+        let (hash, _, files) = &checkout;
+        assert_eq!(files[Path::new("tool.sh")], "echo 1\n");
+        assert!(!files.contains_key(Path::new("lib/lib.txt")));
+        assert!(!files.keys().any(|f| f.starts_with(".git")));
+        assert_eq!(build("git-again", &pipeline("#f"), "git"), checkout);
+
+        let with_submodules = build("git-submodules", &pipeline("#t"), "git");
+        assert_ne!(with_submodules.0, *hash);
+        assert_eq!(with_submodules.2[Path::new("lib/lib.txt")], "lib\n");
+        assert!(!with_submodules.2.keys().any(|f| f.ends_with(".git")));
+        assert_eq!(
+            build("git-submodules-again", &pipeline("#t"), "git"),
+            with_submodules
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unpack_archives() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/steel-modules/tests/fixtures");
+        let expected = BTreeMap::from([
+            (PathBuf::from("tree/a.txt"), String::from("a\n")),
+            (PathBuf::from("tree/sub/b.txt"), String::from("b\n")),
+        ]);
+        for archive in ["tree.tar.gz", "tree.zip"] {
+            let pipeline = format!(
+                r#"(define tree (unpack! (file! "{}")))
+                   (output! "tree" : tree)"#,
+                fixtures.join(archive).display()
+            );
+            let test = format!("unpack-{}", archive);
+            let unpacked = build(&test, &pipeline, "unpack");
+            assert_eq!(unpacked.2, expected);
+            let again = build(&format!("{}-again", test), &pipeline, "unpack");
+            assert_eq!(again, unpacked);
+        }
+    }
+
+    #[test]
+    fn fetch_rejects_scheme() {
+        let mut e = engine(None);
+        let result = e.run(format!(
+            r#"(fetch! "ftp://example.org/reference.fa" "{}")"#,
+            "0".repeat(64)
+        ));
+        assert!(result.is_err());
+    }
 }
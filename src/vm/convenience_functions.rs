@@ -9,7 +9,7 @@ fn gb(x: u64) -> u64{
 
 /// convert x hours to y minutes
 fn hours(x: u64) -> u64{
-    x * 64
+    x * 60
 }
 
 pub fn register_steel_functions(vm: &mut Engine){